@@ -269,6 +269,7 @@ const constants = {
     TASK_CHANGE: 0x04,
     TASK_CONFIRM: 0x05,
     DATA_REPORT: 0x06,
+    SUBSCRIPTION: 0x07,
     NOTIFICATION: 0xFF
   },
   show_identity: {
@@ -338,7 +339,7 @@ class Packet {
   }
 
   static wrap (data) {
-    const { SHOW_IDENTITY, RATE_LIMIT, TASK_APPLICATION, TASK_CHANGE, TASK_CONFIRM, DATA_REPORT, NOTIFICATION } = constants.packetId;
+    const { SHOW_IDENTITY, RATE_LIMIT, TASK_APPLICATION, TASK_CHANGE, TASK_CONFIRM, DATA_REPORT, SUBSCRIPTION, NOTIFICATION } = constants.packetId;
     let id;
     if (data instanceof ShowIdentity) id = SHOW_IDENTITY;
     else if (data instanceof RateLimit) id = RATE_LIMIT;
@@ -346,6 +347,7 @@ class Packet {
     else if (data instanceof TaskChange) id = TASK_CHANGE;
     else if (data instanceof TaskConfirm) id = TASK_CONFIRM;
     else if (data instanceof DataReport) id = DATA_REPORT;
+    else if (data instanceof Subscription) id = SUBSCRIPTION;
     else if (data instanceof Notification) id = NOTIFICATION;
     else throw new Error('Unexpected data!');
     const buf = (data && data.toArrayBuffer && data.toArrayBuffer()) || data;
@@ -457,6 +459,22 @@ class DataReport {
   }
 }
 
+class Subscription {
+  constructor (categories) {
+    this.categoryCount = categories.length;
+    this.categories = categories;
+  }
+
+  toArrayBuffer () {
+    const bytes = new Bytes();
+    bytes.putVarInt(this.categoryCount);
+    for (let i = 0; i < this.categories.length; i++) {
+      bytes.putVarInt(this.categories[i]);
+    }
+    return bytes.toArrayBuffer();
+  }
+}
+
 class Notification {
   constructor (category, message, token) {
     this.category = category;
@@ -528,6 +546,9 @@ const HANDLE_MAP = (() => {
       labour.tokenBucket.interval = data.interval;
       labour.tokenBucket.maxBurst = data.maxBurst;
       if (labour.state === HANDSHAKING) {
+        if (labour.config.subscriptions instanceof Array) {
+          labour.sendData(new Subscription(labour.config.subscriptions));
+        }
         const previousTask = labour.config.previousTask;
         if (previousTask && previousTask.roomCount !== undefined && previousTask.roomIds instanceof Array) {
           labour.sendData(new TaskConfirm(previousTask.roomCount, previousTask.roomIds));
@@ -0,0 +1,161 @@
+use crate::guard::{format_time, Target};
+use crate::labour::message::{Revalidate, UpdateRateLimit};
+use crate::packet::structs::VarInt;
+use crate::rate_limit::is_valid;
+use crate::settings::RateLimit;
+use crate::{GUARD, RATE_LIMITS, ROSTER, TOKENS};
+use std::str::FromStr;
+
+const HELP: &[(&str, &str)] = &[
+    ("stop", "Close all connections and stop server."),
+    (
+        "reload",
+        "Reload token files and sack labours whose token was revoked.",
+    ),
+    (
+        "ban <ip|cidr|token> [hours] [reason]",
+        "Ban and close the connections of an ip, an address block such as 10.0.0.0/8 or a token. Hours default to the ban schedule, 0 means permanent.",
+    ),
+    ("unban <ip|cidr|token>", "Lift the ban of an ip, an address block or a token."),
+    ("bans", "List active bans with their expiry."),
+    (
+        "kicks <ip|token>",
+        "List kick records of an ip or a token inside the kick window.",
+    ),
+    ("ratelimit", "List the rate limits."),
+    (
+        "ratelimit global <interval> <max_burst>",
+        "Change the rate limit of all labours without a more specific one.",
+    ),
+    (
+        "ratelimit <category|token> <key> [<interval> <max_burst>]",
+        "Change the rate limit of a category or a token, or fall back to the global one if omitted.",
+    ),
+    ("help", "Show this help."),
+];
+
+/// Run a console command other than `stop`.
+pub fn execute(line: &str) {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    match (args[0], &args[1..]) {
+        ("reload", []) => {
+            TOKENS.reload();
+            ROSTER.broadcast(Revalidate);
+        }
+        ("ban", [target, rest @ ..]) => {
+            let target = Target::from(*target);
+            let (hours, rest) = match rest.split_first() {
+                Some((hours, rest)) if i64::from_str(hours).is_ok() => {
+                    match u32::from_str(hours) {
+                        Ok(hours) => (Some(hours as i64), rest),
+                        Err(_) => {
+                            println!("Usage: ban <ip|cidr|token> [hours] [reason], hours must be 0 or more.");
+                            return;
+                        }
+                    }
+                }
+                _ => (None, rest),
+            };
+            let reason = if rest.is_empty() {
+                "banned by the operator".to_owned()
+            } else {
+                rest.join(" ")
+            };
+            match GUARD.ban_target(&target, hours, &reason) {
+                Some(t) => println!("Banned {} until '{}'.", target, format_time(t)),
+                None => println!("{} is on the allowlist.", target),
+            }
+        }
+        ("unban", [target]) => {
+            let target = Target::from(*target);
+            if GUARD.unban(&target) {
+                println!("Unbanned {}.", target);
+            } else {
+                println!("{} is not banned.", target);
+            }
+        }
+        ("bans", []) => {
+            let bans = GUARD.bans();
+            for (target, t) in &bans {
+                println!("{} until '{}'", target, format_time(*t));
+            }
+            println!("{} active bans.", bans.len());
+        }
+        ("kicks", [target]) => {
+            let target = Target::from(*target);
+            let records = GUARD.kicks(&target);
+            for (t, w) in &records {
+                println!("'{}' weight {}", format_time(*t), w);
+            }
+            println!(
+                "{} has {} kick records with a total weight of {}.",
+                target,
+                records.len(),
+                records.iter().map(|(_, w)| w).sum::<i32>()
+            );
+        }
+        ("ratelimit", []) => {
+            println!("global: {}", format_rate_limit(&RATE_LIMITS.global()));
+            for (category, rate_limit) in RATE_LIMITS.categories() {
+                println!("category {}: {}", category, format_rate_limit(&rate_limit));
+            }
+            for (token, rate_limit) in RATE_LIMITS.tokens() {
+                println!("token '{}': {}", token, format_rate_limit(&rate_limit));
+            }
+        }
+        ("ratelimit", ["global", rest @ ..]) => match parse_rate_limit(rest) {
+            Some(Some(rate_limit)) => {
+                RATE_LIMITS.set_global(rate_limit);
+                ROSTER.broadcast(UpdateRateLimit);
+            }
+            _ => println!("Usage: ratelimit global <interval> <max_burst>"),
+        },
+        ("ratelimit", ["category", category, rest @ ..]) => {
+            match (VarInt::from_str(category), parse_rate_limit(rest)) {
+                (Ok(category), Some(rate_limit)) => {
+                    RATE_LIMITS.set_category(category, rate_limit);
+                    ROSTER.broadcast(UpdateRateLimit);
+                }
+                _ => println!("Usage: ratelimit category <category> [<interval> <max_burst>]"),
+            }
+        }
+        ("ratelimit", ["token", token, rest @ ..]) => match parse_rate_limit(rest) {
+            Some(rate_limit) => {
+                RATE_LIMITS.set_token(token, rate_limit);
+                ROSTER.broadcast(UpdateRateLimit);
+            }
+            None => println!("Usage: ratelimit token <token> [<interval> <max_burst>]"),
+        },
+        ("help", []) => {
+            for (usage, description) in HELP {
+                println!("{}: {}", usage, description);
+            }
+        }
+        _ => println!("Unknown command \"{}\", type \"help\" for help.", line),
+    }
+}
+
+/// Parse `<interval> <max_burst>`, no arguments means no rate limit.
+fn parse_rate_limit(args: &[&str]) -> Option<Option<RateLimit>> {
+    match args {
+        [] => Some(None),
+        [interval, max_burst] => {
+            let rate_limit = RateLimit {
+                interval: u32::from_str(interval).ok()?,
+                max_burst: u32::from_str(max_burst).ok()?,
+            };
+            if !is_valid(&rate_limit) {
+                return None;
+            }
+            Some(Some(rate_limit))
+        }
+        _ => None,
+    }
+}
+
+fn format_rate_limit(rate_limit: &RateLimit) -> String {
+    format!(
+        "interval {}ms, max burst {}",
+        rate_limit.interval, rate_limit.max_burst
+    )
+}
@@ -0,0 +1,391 @@
+use crate::labour::handle::publish_report;
+use crate::labour::message::DeliverRelay;
+use crate::packet::constants::{id, show_identity::category};
+use crate::packet::{Packet, PacketData, RateLimit, RelayReport, ServerId, ShowIdentity, ToPacket};
+use crate::settings::Settings;
+use crate::{report, PEERS, ROSTER, SCHEDULER, SETTINGS};
+use actix::io::{SinkWrite, WriteHandler};
+use actix::{
+    Actor, ActorFuture, Addr, AsyncContext, Context, ContextFutureSpawner, Handler, Running,
+    SpawnHandle, StreamHandler, WrapFuture,
+};
+use actix_codec::Framed;
+use actix_web::web::{Buf, Bytes};
+use awc::error::WsProtocolError;
+use awc::ws::{Codec, Frame, Message};
+use awc::BoxedSocket;
+use dashmap::DashMap;
+use futures::stream::{SplitSink, StreamExt};
+use governor::state::{InMemoryState, NotKeyed};
+use governor::{clock, Quota, RateLimiter};
+use log::{debug, info, warn};
+use std::cmp::max;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Outbound connections to peer servers which take part in relaying reports, and
+/// the servers connected either way which share the rooms.
+#[derive(Debug)]
+pub struct Peers {
+    peers: DashMap<u64, Addr<Peer>>,
+    /// Server connections either way by their id, with the server ID once it is known.
+    servers: Mutex<HashMap<u64, Option<String>>>,
+    /// Relayed reports which came back to this server since the last count.
+    loops: AtomicU64,
+}
+
+impl Peers {
+    pub fn new() -> Peers {
+        Peers {
+            peers: DashMap::new(),
+            servers: Mutex::new(HashMap::new()),
+            loops: AtomicU64::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Send an encoded Relay Report once to every server other than the one it
+    /// came from and its origin. Connections of an unknown server get it each.
+    pub fn relay(&self, from: u64, origin: &str, data: Bytes) {
+        let mut targets = Vec::new();
+        {
+            let servers = self.servers.lock().unwrap();
+            let sender = servers.get(&from).cloned().flatten();
+            let mut ids: Vec<(&u64, &Option<String>)> = servers.iter().collect();
+            ids.sort_by_key(|(id, _)| **id);
+            let mut seen = HashSet::new();
+            for (id, server) in ids {
+                let wanted = match server {
+                    Some(s) => s != origin && Some(s) != sender.as_ref() && seen.insert(s),
+                    None => *id != from,
+                };
+                if wanted {
+                    targets.push(*id);
+                }
+            }
+        }
+        for id in targets {
+            match self.peers.get(&id).map(|v| v.value().clone()) {
+                Some(addr) => addr.do_send(DeliverRelay(data.clone())),
+                None => {
+                    if let Some(addr) = ROSTER.get(id) {
+                        addr.do_send(DeliverRelay(data.clone()));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Number of servers connected either way.
+    pub fn server_count(&self) -> usize {
+        server_ids(&self.servers.lock().unwrap()).len()
+    }
+
+    /// ID of the server behind a connection, if it has been told.
+    pub fn server_of(&self, id: u64) -> Option<String> {
+        self.servers.lock().unwrap().get(&id).cloned().flatten()
+    }
+
+    /// Record a server connection whose server ID is not known yet.
+    pub fn register(&self, id: u64) {
+        self.servers.lock().unwrap().entry(id).or_insert(None);
+    }
+
+    /// Record the server behind a connection and reshard the rooms.
+    pub fn join(&self, id: u64, server_id: String) {
+        let mut servers = self.servers.lock().unwrap();
+        servers.insert(id, Some(server_id));
+        ROSTER.assign(SCHEDULER.reshard(server_ids(&servers)));
+    }
+
+    /// Forget a closed server connection and take over the rooms of its server.
+    pub fn leave(&self, id: u64) {
+        let mut servers = self.servers.lock().unwrap();
+        if let Some(Some(_)) = servers.remove(&id) {
+            ROSTER.assign(SCHEDULER.reshard(server_ids(&servers)));
+        }
+    }
+
+    /// Take the number of dropped loops and reset it.
+    pub fn take_loops(&self) -> u64 {
+        self.loops.swap(0, Ordering::Relaxed)
+    }
+}
+
+fn server_ids(servers: &HashMap<u64, Option<String>>) -> BTreeSet<String> {
+    servers.values().flatten().cloned().collect()
+}
+
+/// Publish a report relayed by a server, unless it has come back to its origin,
+/// passed too many servers or been seen before through another path.
+pub fn receive_relay(from: u64, reporter: &str, data: &RelayReport) {
+    let (why, looped) = if data.origin == SETTINGS.federation.server_id {
+        ("back at its origin", true)
+    } else if data.hops > SETTINGS.federation.max_hops {
+        ("over the hop limit", false)
+    } else {
+        match publish_report(from, reporter, &data.origin, data.hops, &data.report) {
+            report::Check::Duplicate => ("seen before", true),
+            _ => return,
+        }
+    };
+    // A copy straight from the origin only took a parallel path, not a loop.
+    if looped && PEERS.server_of(from).as_ref() != Some(&data.origin) {
+        PEERS.loops.fetch_add(1, Ordering::Relaxed);
+    }
+    debug!(
+        "Drop relayed report {} '{}' in room '{}' from server '{}' after {} hops, {}.",
+        data.report.category, data.report.id, data.report.room_id, data.origin, data.hops, why
+    );
+}
+
+type PeerSink = SplitSink<Framed<BoxedSocket, Codec>, Message>;
+
+/// A connection to a peer server, where this server shows itself as a labour of
+/// the SERVER category. It is dialed again whenever it is lost.
+pub struct Peer {
+    id: u64,
+    url: String,
+    token: String,
+    reconnect_interval: Duration,
+    sink: Option<SinkWrite<Message, PeerSink>>,
+    writer: Option<SpawnHandle>,
+    stream: Option<SpawnHandle>,
+    heartbeat: Option<SpawnHandle>,
+    /// The rate limit of the peer, so that relaying does not get this server kicked.
+    rate_limiter: Option<RateLimiter<NotKeyed, InMemoryState, clock::DefaultClock>>,
+}
+
+impl Peer {
+    pub fn new(url: String, settings: &Settings) -> Peer {
+        Peer {
+            id: ROSTER.next_id(),
+            url,
+            token: settings.federation.token.clone(),
+            reconnect_interval: Duration::from_millis(settings.federation.reconnect_interval),
+            sink: None,
+            writer: None,
+            stream: None,
+            heartbeat: None,
+            rate_limiter: None,
+        }
+    }
+
+    fn connect(&mut self, ctx: &mut Context<Self>) {
+        let url = self.url.clone();
+        async move { awc::Client::new().ws(url).connect().await }
+            .into_actor(self)
+            .map(|res, peer, ctx| match res {
+                Ok((_, framed)) => {
+                    info!("Connected to peer '{}'.", peer.url);
+                    let (sink, stream) = framed.split();
+                    let sink = SinkWrite::new(sink, ctx);
+                    peer.writer = Some(sink.handle());
+                    peer.sink = Some(sink);
+                    peer.stream = Some(ctx.add_stream(stream));
+                    peer.send(
+                        ShowIdentity {
+                            category: category::SERVER,
+                            token: peer.token.clone(),
+                        }
+                        .to_packet(),
+                    );
+                    peer.send(
+                        ServerId {
+                            server_id: SETTINGS.federation.server_id.clone(),
+                        }
+                        .to_packet(),
+                    );
+                    PEERS.peers.insert(peer.id, ctx.address());
+                    PEERS.register(peer.id);
+                }
+                Err(e) => {
+                    warn!("Can't connect to peer '{}': {}.", peer.url, e);
+                    peer.reconnect(ctx);
+                }
+            })
+            .spawn(ctx);
+    }
+
+    fn reconnect(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_later(self.reconnect_interval, |peer, ctx| peer.connect(ctx));
+    }
+
+    /// Drop the connection and dial again later. The writer may be the caller,
+    /// so its future is only cancelled here and never touched.
+    fn disconnect(&mut self, ctx: &mut Context<Self>) {
+        if self.sink.take().is_none() {
+            return;
+        }
+        info!(
+            "Disconnected from peer '{}', reconnecting in {}s.",
+            self.url,
+            self.reconnect_interval.as_secs_f32()
+        );
+        PEERS.peers.remove(&self.id);
+        PEERS.leave(self.id);
+        let handles = vec![
+            self.writer.take(),
+            self.stream.take(),
+            self.heartbeat.take(),
+        ];
+        for handle in handles.into_iter().flatten() {
+            ctx.cancel_future(handle);
+        }
+        self.rate_limiter = None;
+        self.reconnect(ctx);
+    }
+
+    fn send(&mut self, packet: Packet) {
+        if let Some(sink) = &mut self.sink {
+            sink.write(Message::Binary(packet.to_bytes()));
+        }
+    }
+
+    /// Take a token from the rate limit of the peer, `false` if there is none left.
+    fn check_rate(&self) -> bool {
+        self.rate_limiter
+            .as_ref()
+            .map_or(false, |limiter| limiter.check().is_ok())
+    }
+
+    fn rate_limit(&mut self, ctx: &mut Context<Self>, data: RateLimit) {
+        let (interval, max_burst) = match (
+            NonZeroU32::new(data.interval as u32),
+            NonZeroU32::new(data.max_burst as u32),
+        ) {
+            (Some(interval), Some(max_burst)) => (interval, max_burst),
+            _ => return,
+        };
+        info!(
+            "Peer '{}' limits the rate to {}ms with a burst of {}.",
+            self.url, interval, max_burst
+        );
+        let quota = Quota::with_period(Duration::from_millis(interval.get() as u64))
+            .unwrap()
+            .allow_burst(max_burst);
+        self.rate_limiter = Some(RateLimiter::direct(quota));
+        // Ping at half the heartbeat timeout of the peer, but at most once a second.
+        let period = max(
+            Duration::from_millis(interval.get() as u64 * max_burst.get() as u64 / 2),
+            Duration::from_secs(1),
+        );
+        if let Some(handle) = self.heartbeat.take() {
+            ctx.cancel_future(handle);
+        }
+        self.heartbeat = Some(ctx.run_interval(period, |peer, ctx| {
+            if peer.check_rate() {
+                if let Some(sink) = &mut peer.sink {
+                    sink.write(Message::Ping(Bytes::new()));
+                }
+            }
+        }));
+    }
+
+    fn receive_binary(&mut self, ctx: &mut Context<Self>, mut bin: Bytes) {
+        while bin.has_remaining() {
+            let mut pkt = match Packet::read_from_bytes(&mut bin) {
+                Some(pkt) => pkt,
+                None => {
+                    warn!("Peer '{}' sends an invalid packet.", self.url);
+                    return;
+                }
+            };
+            match pkt.id {
+                id::RATE_LIMIT => {
+                    if let Some(data) = RateLimit::read_from_bytes(&mut pkt.data) {
+                        self.rate_limit(ctx, data);
+                    }
+                }
+                id::SERVER_ID => {
+                    if let Some(data) = ServerId::read_from_bytes(&mut pkt.data) {
+                        info!("Peer '{}' is server '{}'.", self.url, data.server_id);
+                        PEERS.join(self.id, data.server_id);
+                    }
+                }
+                id::RELAY_REPORT => {
+                    if let Some(data) = RelayReport::read_from_bytes(&mut pkt.data) {
+                        if report::is_valid_category(data.report.category) {
+                            receive_relay(self.id, &self.url, &data);
+                        }
+                    }
+                }
+                _ => debug!("Ignore packet {:#04x} from peer '{}'.", pkt.id, self.url),
+            }
+        }
+    }
+}
+
+impl Actor for Peer {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.connect(ctx);
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        PEERS.peers.remove(&self.id);
+        PEERS.leave(self.id);
+    }
+}
+
+impl Handler<DeliverRelay> for Peer {
+    type Result = ();
+
+    fn handle(&mut self, msg: DeliverRelay, ctx: &mut Self::Context) {
+        if self.sink.is_none() {
+            return;
+        }
+        if !self.check_rate() {
+            warn!("Drop a report to peer '{}' over its rate limit.", self.url);
+            return;
+        }
+        if let Some(sink) = &mut self.sink {
+            sink.write(Message::Binary(msg.0));
+        }
+    }
+}
+
+impl StreamHandler<Result<Frame, WsProtocolError>> for Peer {
+    fn handle(&mut self, msg: Result<Frame, WsProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(Frame::Binary(bin)) => self.receive_binary(ctx, bin),
+            Ok(Frame::Ping(bytes)) => {
+                if let Some(sink) = &mut self.sink {
+                    sink.write(Message::Pong(bytes));
+                }
+            }
+            Ok(Frame::Close(reason)) => {
+                info!("Peer '{}' closes the connection: {:?}.", self.url, reason);
+                self.disconnect(ctx);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Protocol error from peer '{}': {}.", self.url, e);
+                self.disconnect(ctx);
+            }
+        }
+    }
+
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        self.disconnect(ctx);
+    }
+}
+
+impl WriteHandler<WsProtocolError> for Peer {
+    fn error(&mut self, err: WsProtocolError, ctx: &mut Self::Context) -> Running {
+        warn!("Can't write to peer '{}': {}.", self.url, err);
+        self.disconnect(ctx);
+        Running::Continue
+    }
+
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        self.disconnect(ctx);
+    }
+}
@@ -1,42 +1,170 @@
-use std::net::{IpAddr, SocketAddr};
+use std::net::IpAddr;
 
+use crate::guard::cidr::Cidr;
 use crate::labour::Labour;
 use crate::settings;
 use crate::settings::Settings;
+use crate::ROSTER;
 use actix::ActorContext;
 use actix_web_actors::ws::{CloseReason, WebsocketContext};
 use chrono::{Local, NaiveDate, NaiveDateTime};
 use dashmap::DashMap;
-use log::info;
-use std::collections::HashMap;
+use log::{info, warn};
+use std::cmp::min;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::Display;
+use std::hash::Hash;
+use std::str::FromStr;
+use std::sync::RwLock;
 
+pub mod cidr;
 pub mod reason;
+mod record;
+
+/// Ban expiry of a permanent ban.
+const PERMANENT: i64 = i64::MAX;
+
+pub fn format_time(t: i64) -> String {
+    if t == PERMANENT {
+        "forever".to_owned()
+    } else {
+        NaiveDateTime::from_timestamp(t, 0).to_string()
+    }
+}
+
+/// Timestamps and weights of kicks.
+pub type KickRecords = Vec<(i64, i32)>;
+
+/// An IP, an address block or a token given by the operator.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    Ip(IpAddr),
+    Cidr(Cidr),
+    Token(String),
+}
+
+impl From<&str> for Target {
+    fn from(s: &str) -> Self {
+        if let Ok(ip) = IpAddr::from_str(s) {
+            return Target::Ip(ip);
+        }
+        if s.contains('/') {
+            if let Ok(cidr) = Cidr::from_str(s) {
+                return Target::Cidr(cidr);
+            }
+        }
+        Target::Token(s.to_owned())
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Ip(ip) => write!(f, "ip '{}'", ip),
+            Target::Cidr(cidr) => write!(f, "cidr '{}'", cidr),
+            Target::Token(token) => write!(f, "token '{}'", token),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Guard {
     settings: settings::Guard,
-    kicked_ips: DashMap<IpAddr, i32>,
-    kicked_tokens: DashMap<String, i32>,
+    kicked_ips: DashMap<IpAddr, KickRecords>,
+    kicked_tokens: DashMap<String, KickRecords>,
     banned_ips: DashMap<IpAddr, i64>,
     banned_tokens: DashMap<String, i64>,
+    ban_history_ips: DashMap<IpAddr, u32>,
+    ban_history_tokens: DashMap<String, u32>,
+    banned_cidrs: DashMap<Cidr, i64>,
+    ban_history_cidrs: DashMap<Cidr, u32>,
+    /// Permanent bans from the config file, which are not saved.
+    configured_cidrs: HashSet<Cidr>,
+    /// Prefix lengths of `banned_cidrs` for IPv4 and IPv6, so that `check_addr`
+    /// does one lookup per prefix length instead of one per block.
+    cidr_prefixes: RwLock<[Vec<u8>; 2]>,
+    allowed_ips: HashSet<IpAddr>,
+    allowed_tokens: HashSet<String>,
 }
 
 impl Guard {
     pub fn new(settings: &Settings) -> Guard {
-        Guard {
+        let mut allowed_ips = HashSet::new();
+        let mut allowed_tokens = HashSet::new();
+        for s in &settings.guard.allowlist {
+            match Target::from(s.as_str()) {
+                Target::Ip(ip) => allowed_ips.insert(ip),
+                Target::Token(token) => allowed_tokens.insert(token),
+                Target::Cidr(_) => {
+                    warn!(
+                        "Address blocks are not supported in the allowlist: '{}'.",
+                        s
+                    );
+                    false
+                }
+            };
+        }
+        let mut configured_cidrs = HashSet::new();
+        for s in &settings.guard.banned_cidrs {
+            match Cidr::from_str(s) {
+                Ok(cidr) => {
+                    configured_cidrs.insert(cidr);
+                }
+                Err(_) => warn!("Skip invalid cidr in guard.banned_cidrs: '{}'.", s),
+            }
+        }
+        let guard = Guard {
             settings: settings.guard.clone(),
             kicked_ips: DashMap::new(),
             kicked_tokens: DashMap::new(),
             banned_ips: DashMap::new(),
             banned_tokens: DashMap::new(),
+            ban_history_ips: DashMap::new(),
+            ban_history_tokens: DashMap::new(),
+            banned_cidrs: configured_cidrs.iter().map(|c| (*c, PERMANENT)).collect(),
+            ban_history_cidrs: DashMap::new(),
+            configured_cidrs,
+            cidr_prefixes: RwLock::new([Vec::new(), Vec::new()]),
+            allowed_ips,
+            allowed_tokens,
+        };
+        guard.load();
+        guard.refresh_prefixes();
+        guard
+    }
+
+    /// Collect the prefix lengths in use after `banned_cidrs` has changed.
+    fn refresh_prefixes(&self) {
+        let mut prefixes = [BTreeSet::new(), BTreeSet::new()];
+        for e in self.banned_cidrs.iter() {
+            prefixes[e.key().is_ipv6() as usize].insert(e.key().prefix());
         }
+        *self.cidr_prefixes.write().unwrap() = [
+            prefixes[0].iter().cloned().collect(),
+            prefixes[1].iter().cloned().collect(),
+        ];
     }
 
-    pub fn check_addr(&self, addr: &SocketAddr) -> bool {
-        if let Some(t) = self.banned_ips.get(&addr.ip()) {
-            return t.value() < &Local::now().timestamp();
+    /// Returns `true` if the IP or the token is on the allowlist.
+    pub fn is_allowed(&self, ip: &IpAddr, token: &str) -> bool {
+        self.allowed_ips.contains(ip) || self.allowed_tokens.contains(token)
+    }
+
+    pub fn check_addr(&self, ip: &IpAddr) -> bool {
+        let ip = *ip;
+        if self.allowed_ips.contains(&ip) {
+            return true;
         }
-        true
+        let now = Local::now().timestamp();
+        if self.banned_ips.get(&ip).map_or(false, |t| *t.value() > now) {
+            return false;
+        }
+        let prefixes = self.cidr_prefixes.read().unwrap();
+        !prefixes[ip.is_ipv6() as usize].iter().any(|prefix| {
+            Cidr::new(ip, *prefix)
+                .and_then(|cidr| self.banned_cidrs.get(&cidr))
+                .map_or(false, |t| *t.value() > now)
+        })
     }
 
     pub fn check_token(&self, token: &String) -> bool {
@@ -46,22 +174,55 @@ impl Guard {
         true
     }
 
+    /// Returns `false` if the IP or its subnet has more connections than allowed.
+    pub fn check_connections(&self, ip: &IpAddr) -> bool {
+        let max = self.settings.max_connections_per_ip;
+        if max > 0 && ROSTER.count_ip(ip) > max {
+            info!("Too many connections from ip '{}'.", ip);
+            return false;
+        }
+        let max = self.settings.max_connections_per_subnet;
+        if max > 0 && ROSTER.count_subnet(ip) > max {
+            info!("Too many connections from subnet of ip '{}'.", ip);
+            return false;
+        }
+        true
+    }
+
+    /// Returns `false` if the token is used by more connections than allowed.
+    pub fn check_token_connections(&self, token: &str) -> bool {
+        let max = self.settings.max_connections_per_token;
+        if max > 0 && ROSTER.count_token(token) > max {
+            info!("Too many connections with token '{}'.", token);
+            return false;
+        }
+        true
+    }
+
     pub fn kick(
         &self,
         labour: &Labour,
         ctx: &mut WebsocketContext<Labour>,
         reason: reason::kick::Reason,
     ) {
+        let weight = self.kick_weight(reason);
         let reason = reason::kick::CODE_MAP.get(&reason).unwrap().value().clone();
-        let ip = &labour.connection_info.peer_addr.ip();
-        let v1 = if let Some(v) = self.kicked_ips.get(ip) {
-            v.value() + 1
-        } else {
-            1
-        };
-        self.kicked_ips.insert(*ip, v1);
+        let now = Local::now().timestamp();
+        let ip = &labour.connection_info.client_ip;
+        if self.is_allowed(ip, &labour.token) {
+            info!(
+                "Close allowlisted ip '{}' without recording the kick. Reason: {}.",
+                ip,
+                reason
+                    .description
+                    .as_ref()
+                    .unwrap_or(&"no reason".to_owned())
+            );
+            return self.sack(ctx, Some(reason));
+        }
+        let v1 = self.record_kick(&self.kicked_ips, *ip, now, weight);
         info!(
-            "Kick ip '{}', which has {} records. Reason: {}.",
+            "Kick ip '{}', which has {} weighted records. Reason: {}.",
             ip,
             v1,
             reason
@@ -70,27 +231,63 @@ impl Guard {
                 .unwrap_or(&"no reason".to_owned())
         );
         let token = &labour.token;
-        let v2 = if let Some(v) = self.kicked_tokens.get(token) {
-            v.value() + 1
+        let v2 = if token.is_empty() {
+            0
         } else {
-            1
+            let v2 = self.record_kick(&self.kicked_tokens, token.clone(), now, weight);
+            info!(
+                "Kick token '{}', which has {} weighted records. Reason: {}.",
+                token,
+                v2,
+                reason
+                    .description
+                    .as_ref()
+                    .unwrap_or(&"no reason".to_owned())
+            );
+            v2
         };
-        self.kicked_tokens.insert(token.clone(), v2);
-        info!(
-            "Kick token '{}', which has {} records. Reason: {}.",
-            token,
-            v2,
-            reason
-                .description
-                .as_ref()
-                .unwrap_or(&"no reason".to_owned())
-        );
-        if v1 % self.settings.kick_count == 0 || v2 % self.settings.kick_count == 0 {
+        if v1 >= self.settings.kick_count || v2 >= self.settings.kick_count {
+            self.kicked_ips.remove(ip);
+            self.kicked_tokens.remove(token);
             return self.ban(labour, ctx, reason::ban::Reason::TooManyKicks);
         }
         self.sack(ctx, Some(reason));
     }
 
+    fn kick_weight(&self, reason: reason::kick::Reason) -> i32 {
+        self.settings
+            .kick_weights
+            .get(reason.name())
+            .cloned()
+            .unwrap_or(1)
+    }
+
+    /// Add a kick record and return the total weight of the records inside the window.
+    fn record_kick<K: Eq + Hash>(
+        &self,
+        map: &DashMap<K, KickRecords>,
+        key: K,
+        now: i64,
+        weight: i32,
+    ) -> i32 {
+        let since = now - self.settings.kick_window * 3600;
+        let mut records = map.entry(key).or_default();
+        records.retain(|(t, _)| *t > since);
+        records.push((now, weight));
+        records.iter().map(|(_, w)| *w).sum()
+    }
+
+    /// Drop the kick records which have left the window.
+    pub fn prune(&self) {
+        let since = Local::now().timestamp() - self.settings.kick_window * 3600;
+        let f = |records: &mut KickRecords| {
+            records.retain(|(t, _)| *t > since);
+            !records.is_empty()
+        };
+        self.kicked_ips.retain(|_, records| f(records));
+        self.kicked_tokens.retain(|_, records| f(records));
+    }
+
     pub fn ban(
         &self,
         labour: &Labour,
@@ -98,34 +295,230 @@ impl Guard {
         reason: reason::ban::Reason,
     ) {
         let reason = reason::ban::CODE_MAP.get(&reason).unwrap().value().clone();
-        let t = Local::now().timestamp() + self.settings.ban_time * 3600;
-        let ip = &labour.connection_info.peer_addr.ip();
-        if !self.banned_ips.contains_key(ip) {
-            self.banned_ips.insert(*ip, t);
+        let description = reason
+            .description
+            .clone()
+            .unwrap_or_else(|| "no reason".to_owned());
+        let ip = &labour.connection_info.client_ip;
+        let token = &labour.token;
+        if self.is_allowed(ip, token) {
             info!(
-                "Ban ip '{}' until '{}'. Reason: {}.",
-                ip,
-                NaiveDateTime::from_timestamp(t, 0),
-                reason
-                    .description
-                    .as_ref()
-                    .unwrap_or(&"no reason".to_owned())
+                "Close allowlisted ip '{}' without banning it. Reason: {}.",
+                ip, description
             );
+            return self.sack(ctx, Some(reason));
         }
-        let token = &labour.token;
-        if !self.banned_tokens.contains_key(token) {
-            self.banned_tokens.insert(token.clone(), t);
+        if let Some(cidr) = self.ban_addr(ip, token, &description) {
+            ROSTER.sack_cidr(&cidr, Some(reason.clone()));
+        }
+        ROSTER.sack_ip(ip, Some(reason.clone()));
+        if !token.is_empty() {
+            ROSTER.sack_token(token, Some(reason.clone()));
+        }
+        self.sack(ctx, Some(reason));
+    }
+
+    /// Ban the IP and the token, if any, following the ban schedule. The /64 of an
+    /// IPv6 address is banned as well if `ban_ipv6_subnet` is set, which is returned.
+    fn ban_addr(&self, ip: &IpAddr, token: &str, description: &str) -> Option<Cidr> {
+        self.ban_key(
+            "ip",
+            &self.banned_ips,
+            &self.ban_history_ips,
+            ip,
+            None,
+            description,
+        );
+        if !token.is_empty() {
+            self.ban_key(
+                "token",
+                &self.banned_tokens,
+                &self.ban_history_tokens,
+                &token.to_owned(),
+                None,
+                description,
+            );
+        }
+        if !(self.settings.ban_ipv6_subnet && ip.is_ipv6()) {
+            return None;
+        }
+        let cidr = Cidr::new(*ip, 64).unwrap();
+        self.ban_key(
+            "cidr",
+            &self.banned_cidrs,
+            &self.ban_history_cidrs,
+            &cidr,
+            None,
+            description,
+        );
+        self.refresh_prefixes();
+        Some(cidr)
+    }
+
+    /// Ban the key for the given hours, or for the duration of the next step in
+    /// the ban schedule unless it is already banned. Returns the expiry.
+    fn ban_key<K: Eq + Hash + Clone + Display>(
+        &self,
+        kind: &str,
+        banned: &DashMap<K, i64>,
+        history: &DashMap<K, u32>,
+        key: &K,
+        hours: Option<i64>,
+        description: &str,
+    ) -> i64 {
+        let now = Local::now().timestamp();
+        if let Some(t) = banned.get(key).map(|t| *t.value()) {
+            if hours.is_none() && t > now {
+                return t;
+            }
+        }
+        let count = {
+            let mut count = history.entry(key.clone()).or_insert(0);
+            *count += 1;
+            *count as usize
+        };
+        let schedule = &self.settings.ban_schedule;
+        let step = min(count, schedule.len()).max(1) - 1;
+        let t = match hours.unwrap_or_else(|| schedule.get(step).cloned().unwrap_or(24)) {
+            hours if hours > 0 => now + hours * 3600,
+            _ => PERMANENT,
+        };
+        banned.insert(key.clone(), t);
+        if hours.is_some() {
             info!(
-                "Ban token '{}' until '{}'. Reason: {}.",
-                ip,
-                NaiveDateTime::from_timestamp(t, 0),
-                reason
-                    .description
-                    .as_ref()
-                    .unwrap_or(&"no reason".to_owned())
+                "Ban {} '{}' until '{}' by the operator. Reason: {}.",
+                kind,
+                key,
+                format_time(t),
+                description
+            );
+        } else {
+            info!(
+                "Ban {} '{}' until '{}', step {} of the ban schedule. Reason: {}.",
+                kind,
+                key,
+                format_time(t),
+                step + 1,
+                description
             );
         }
-        self.sack(ctx, Some(reason));
+        t
+    }
+
+    /// Ban the target from the console and close its connections. Hours of `None`
+    /// follow the ban schedule, 0 means permanent. Returns `None` if the target
+    /// is on the allowlist, otherwise the expiry.
+    pub fn ban_target(
+        &self,
+        target: &Target,
+        hours: Option<i64>,
+        description: &str,
+    ) -> Option<i64> {
+        let reason = reason::ban::CODE_MAP
+            .get(&reason::ban::Reason::Banned)
+            .unwrap()
+            .value()
+            .clone();
+        match target {
+            Target::Ip(ip) => {
+                if self.allowed_ips.contains(ip) {
+                    return None;
+                }
+                let t = self.ban_key(
+                    "ip",
+                    &self.banned_ips,
+                    &self.ban_history_ips,
+                    ip,
+                    hours,
+                    description,
+                );
+                ROSTER.sack_ip(ip, Some(reason));
+                Some(t)
+            }
+            Target::Cidr(cidr) => {
+                let t = self.ban_key(
+                    "cidr",
+                    &self.banned_cidrs,
+                    &self.ban_history_cidrs,
+                    cidr,
+                    hours,
+                    description,
+                );
+                self.refresh_prefixes();
+                ROSTER.sack_cidr(cidr, Some(reason));
+                Some(t)
+            }
+            Target::Token(token) => {
+                if self.allowed_tokens.contains(token) {
+                    return None;
+                }
+                let t = self.ban_key(
+                    "token",
+                    &self.banned_tokens,
+                    &self.ban_history_tokens,
+                    token,
+                    hours,
+                    description,
+                );
+                ROSTER.sack_token(token, Some(reason));
+                Some(t)
+            }
+        }
+    }
+
+    /// Lift the ban of the target. Returns `false` if it was not banned.
+    pub fn unban(&self, target: &Target) -> bool {
+        let now = Local::now().timestamp();
+        let t = match target {
+            Target::Ip(ip) => self.banned_ips.remove(ip).map(|(_, t)| t),
+            Target::Cidr(cidr) => {
+                let t = self.banned_cidrs.remove(cidr).map(|(_, t)| t);
+                self.refresh_prefixes();
+                t
+            }
+            Target::Token(token) => self.banned_tokens.remove(token).map(|(_, t)| t),
+        };
+        if t.map_or(false, |t| t > now) {
+            info!("Unban {}.", target);
+            return true;
+        }
+        false
+    }
+
+    /// Active bans with their expiry, the earliest first.
+    pub fn bans(&self) -> Vec<(Target, i64)> {
+        let now = Local::now().timestamp();
+        let mut bans: Vec<(Target, i64)> = self
+            .banned_ips
+            .iter()
+            .map(|e| (Target::Ip(*e.key()), *e.value()))
+            .chain(
+                self.banned_cidrs
+                    .iter()
+                    .map(|e| (Target::Cidr(*e.key()), *e.value())),
+            )
+            .chain(
+                self.banned_tokens
+                    .iter()
+                    .map(|e| (Target::Token(e.key().clone()), *e.value())),
+            )
+            .filter(|(_, t)| *t > now)
+            .collect();
+        bans.sort_by_key(|(_, t)| *t);
+        bans
+    }
+
+    /// Kick records of the target inside the window.
+    pub fn kicks(&self, target: &Target) -> KickRecords {
+        let since = Local::now().timestamp() - self.settings.kick_window * 3600;
+        let records = match target {
+            Target::Ip(ip) => self.kicked_ips.get(ip).map(|r| r.value().clone()),
+            Target::Cidr(_) => None,
+            Target::Token(token) => self.kicked_tokens.get(token).map(|r| r.value().clone()),
+        };
+        let mut records = records.unwrap_or_default();
+        records.retain(|(t, _)| *t > since);
+        records
     }
 
     pub fn sack(&self, ctx: &mut WebsocketContext<Labour>, reason: Option<CloseReason>) {
@@ -133,3 +526,52 @@ impl Guard {
         ctx.stop();
     }
 }
+
+#[test]
+fn test() {
+    let mut settings = Settings::default();
+    let path = std::env::temp_dir().join(format!("guard_test_{}", std::process::id()));
+    settings.guard.record_file = path.to_string_lossy().into_owned();
+    settings.guard.ban_schedule = vec![1, 24, 0];
+    settings.guard.ban_ipv6_subnet = true;
+    let guard = Guard::new(&settings);
+    let now = Local::now().timestamp();
+    let ip = IpAddr::from_str("10.0.0.1").unwrap();
+    let ban = |hours| {
+        guard.ban_key(
+            "ip",
+            &guard.banned_ips,
+            &guard.ban_history_ips,
+            &ip,
+            hours,
+            "test",
+        )
+    };
+    // Each ban takes the next step of the schedule and the last one repeats.
+    let t = ban(None);
+    assert!((t - now - 3600).abs() <= 1);
+    assert_eq!(ban(None), t);
+    guard.banned_ips.insert(ip, now);
+    assert!((ban(None) - now - 24 * 3600).abs() <= 1);
+    guard.banned_ips.insert(ip, now);
+    assert_eq!(ban(None), PERMANENT);
+    guard.banned_ips.insert(ip, now);
+    assert_eq!(ban(None), PERMANENT);
+    assert!((ban(Some(2)) - now - 2 * 3600).abs() <= 1);
+    assert_eq!(ban(Some(0)), PERMANENT);
+    assert!(!guard.check_addr(&ip));
+
+    // Only the IP is banned for IPv4, and the /64 with it for IPv6.
+    let ip = IpAddr::from_str("10.0.1.1").unwrap();
+    assert_eq!(guard.ban_addr(&ip, "token", "test"), None);
+    assert!(!guard.check_addr(&ip));
+    assert!(guard.check_addr(&IpAddr::from_str("10.0.1.2").unwrap()));
+    assert!(!guard.check_token(&"token".to_owned()));
+    let ip = IpAddr::from_str("2001:db8::1").unwrap();
+    assert_eq!(
+        guard.ban_addr(&ip, "", "test"),
+        Some(Cidr::from_str("2001:db8::/64").unwrap())
+    );
+    assert!(!guard.check_addr(&IpAddr::from_str("2001:db8::ffff:1").unwrap()));
+    assert!(guard.check_addr(&IpAddr::from_str("2001:db8:0:1::1").unwrap()));
+}
@@ -0,0 +1,82 @@
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// An address block such as `10.0.0.0/8` or `2001:db8::/64`, with the host bits cleared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Returns `None` if the prefix is longer than the address.
+    pub fn new(ip: IpAddr, prefix: u8) -> Option<Cidr> {
+        let addr = match ip {
+            IpAddr::V4(ip) if prefix <= 32 => {
+                let mask = (!0u32).checked_shl(32 - prefix as u32).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+            }
+            IpAddr::V6(ip) if prefix <= 128 => {
+                let mask = (!0u128).checked_shl(128 - prefix as u32).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+            }
+            _ => return None,
+        };
+        Some(Cidr { addr, prefix })
+    }
+
+    #[inline]
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    #[inline]
+    pub fn is_ipv6(&self) -> bool {
+        self.addr.is_ipv6()
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        ip.is_ipv6() == self.is_ipv6() && Cidr::new(*ip, self.prefix) == Some(*self)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = ();
+
+    /// Parse `addr/prefix`, a bare address is a block of itself.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, prefix) = match s.split_once('/') {
+            Some((ip, prefix)) => (
+                IpAddr::from_str(ip).map_err(|_| ())?,
+                u8::from_str(prefix).map_err(|_| ())?,
+            ),
+            None => {
+                let ip = IpAddr::from_str(s).map_err(|_| ())?;
+                (ip, if ip.is_ipv4() { 32 } else { 128 })
+            }
+        };
+        Cidr::new(ip, prefix).ok_or(())
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[test]
+fn test() {
+    let cidr = Cidr::from_str("10.1.2.3/16").unwrap();
+    assert_eq!(cidr.to_string(), "10.1.0.0/16");
+    assert!(cidr.contains(&IpAddr::from_str("10.1.255.1").unwrap()));
+    assert!(!cidr.contains(&IpAddr::from_str("10.2.0.1").unwrap()));
+    let cidr = Cidr::from_str("2001:db8:0:1:2::/64").unwrap();
+    assert_eq!(cidr.to_string(), "2001:db8:0:1::/64");
+    assert!(cidr.contains(&IpAddr::from_str("2001:db8:0:1:ffff::1").unwrap()));
+    assert!(!cidr.contains(&IpAddr::from_str("10.1.0.0").unwrap()));
+    assert_eq!(Cidr::from_str("0.0.0.0/0").unwrap().prefix(), 0);
+    assert!(Cidr::from_str("10.0.0.0/33").is_err());
+    assert!(Cidr::from_str("token/8").is_err());
+}
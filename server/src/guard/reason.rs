@@ -3,7 +3,7 @@ pub mod kick {
     use dashmap::DashMap;
     use std::lazy::SyncLazy;
 
-    #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash)]
+    #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy)]
     pub enum Reason {
         HeartbeatTimeout,
         ResponseTimeout,
@@ -12,6 +12,23 @@ pub mod kick {
         UnexpectedPacket,
         InvalidPacket,
         IncorrectDataFormat,
+        Unauthorized,
+    }
+
+    impl Reason {
+        /// Name used for the reason in the config file.
+        pub fn name(&self) -> &'static str {
+            match self {
+                Reason::HeartbeatTimeout => "heartbeat_timeout",
+                Reason::ResponseTimeout => "response_timeout",
+                Reason::RateLimit => "rate_limit",
+                Reason::TooManyConnections => "too_many_connections",
+                Reason::UnexpectedPacket => "unexpected_packet",
+                Reason::InvalidPacket => "invalid_packet",
+                Reason::IncorrectDataFormat => "incorrect_data_format",
+                Reason::Unauthorized => "unauthorized",
+            }
+        }
     }
 
     pub static CODE_MAP: SyncLazy<DashMap<Reason, CloseReason>> = SyncLazy::new(|| {
@@ -65,6 +82,13 @@ pub mod kick {
                 description: Some(("incorrect data format".to_owned())),
             },
         );
+        m.insert(
+            Reason::Unauthorized,
+            CloseReason {
+                code: CloseCode::from(4008),
+                description: Some(("unauthorized".to_owned())),
+            },
+        );
         m
     });
 }
@@ -106,3 +130,33 @@ pub mod ban {
         m
     });
 }
+pub mod sack {
+    use actix_web_actors::ws::{CloseCode, CloseReason};
+    use dashmap::DashMap;
+    use std::lazy::SyncLazy;
+
+    #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash)]
+    pub enum Reason {
+        TokenRevoked,
+        KickedByAdmin,
+    }
+
+    pub static CODE_MAP: SyncLazy<DashMap<Reason, CloseReason>> = SyncLazy::new(|| {
+        let mut m: DashMap<Reason, CloseReason> = DashMap::new();
+        m.insert(
+            Reason::TokenRevoked,
+            CloseReason {
+                code: CloseCode::from(4101),
+                description: Some("token revoked".to_owned()),
+            },
+        );
+        m.insert(
+            Reason::KickedByAdmin,
+            CloseReason {
+                code: CloseCode::from(4102),
+                description: Some("kicked by an admin".to_owned()),
+            },
+        );
+        m
+    });
+}
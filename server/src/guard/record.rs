@@ -0,0 +1,217 @@
+use crate::guard::cidr::Cidr;
+use crate::guard::Guard;
+use chrono::Local;
+use log::{error, info, warn};
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+const KICK_IP: &str = "kick_ip";
+const KICK_TOKEN: &str = "kick_token";
+const BAN_IP: &str = "ban_ip";
+const BAN_TOKEN: &str = "ban_token";
+const BAN_HISTORY_IP: &str = "ban_history_ip";
+const BAN_HISTORY_TOKEN: &str = "ban_history_token";
+const BAN_CIDR: &str = "ban_cidr";
+const BAN_HISTORY_CIDR: &str = "ban_history_cidr";
+
+impl Guard {
+    /// Load the records saved by `save`, dropping bans which have already expired.
+    pub(super) fn load(&self) {
+        let path = &self.settings.record_file;
+        if !Path::new(path).is_file() {
+            return;
+        }
+        let s = match std::fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Can't read guard record file '{}': {}.", path, e);
+                return;
+            }
+        };
+        let now = Local::now().timestamp();
+        for (i, line) in s.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            if self.load_line(line, now).is_none() {
+                warn!("Skip malformed guard record at line {}: '{}'.", i + 1, line);
+            }
+        }
+        self.prune();
+        info!(
+            "Loaded guard records: {} kicked ips, {} kicked tokens, {} banned ips, {} banned cidrs, {} banned tokens.",
+            self.kicked_ips.len(),
+            self.kicked_tokens.len(),
+            self.banned_ips.len(),
+            self.banned_cidrs.len(),
+            self.banned_tokens.len()
+        );
+    }
+
+    fn load_line(&self, line: &str, now: i64) -> Option<()> {
+        let mut it = line.split('\t');
+        let kind = it.next()?;
+        let key = it.next()?;
+        let value = i64::from_str(it.next()?).ok()?;
+        match kind {
+            KICK_IP => {
+                let weight = i32::from_str(it.next()?).ok()?;
+                self.kicked_ips
+                    .entry(IpAddr::from_str(key).ok()?)
+                    .or_default()
+                    .push((value, weight));
+            }
+            KICK_TOKEN => {
+                let weight = i32::from_str(it.next()?).ok()?;
+                self.kicked_tokens
+                    .entry(key.to_owned())
+                    .or_default()
+                    .push((value, weight));
+            }
+            BAN_IP => {
+                if value > now {
+                    self.banned_ips.insert(IpAddr::from_str(key).ok()?, value);
+                }
+            }
+            BAN_TOKEN => {
+                if value > now {
+                    self.banned_tokens.insert(key.to_owned(), value);
+                }
+            }
+            BAN_CIDR => {
+                let cidr = Cidr::from_str(key).ok()?;
+                if value > now && !self.configured_cidrs.contains(&cidr) {
+                    self.banned_cidrs.insert(cidr, value);
+                }
+            }
+            BAN_HISTORY_IP => {
+                self.ban_history_ips
+                    .insert(IpAddr::from_str(key).ok()?, value as u32);
+            }
+            BAN_HISTORY_TOKEN => {
+                self.ban_history_tokens.insert(key.to_owned(), value as u32);
+            }
+            BAN_HISTORY_CIDR => {
+                self.ban_history_cidrs
+                    .insert(Cidr::from_str(key).ok()?, value as u32);
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Write all records to `record_file`, replacing it at once.
+    pub fn save(&self) {
+        let mut s = String::new();
+        self.prune();
+        for e in self.kicked_ips.iter() {
+            for (t, w) in e.value() {
+                writeln!(s, "{}\t{}\t{}\t{}", KICK_IP, e.key(), t, w).unwrap();
+            }
+        }
+        for e in self.kicked_tokens.iter().filter(|e| is_savable(e.key())) {
+            for (t, w) in e.value() {
+                writeln!(s, "{}\t{}\t{}\t{}", KICK_TOKEN, e.key(), t, w).unwrap();
+            }
+        }
+        for e in self.banned_ips.iter() {
+            writeln!(s, "{}\t{}\t{}", BAN_IP, e.key(), e.value()).unwrap();
+        }
+        for e in self.banned_tokens.iter().filter(|e| is_savable(e.key())) {
+            writeln!(s, "{}\t{}\t{}", BAN_TOKEN, e.key(), e.value()).unwrap();
+        }
+        for e in self.ban_history_ips.iter() {
+            writeln!(s, "{}\t{}\t{}", BAN_HISTORY_IP, e.key(), e.value()).unwrap();
+        }
+        for e in self
+            .ban_history_tokens
+            .iter()
+            .filter(|e| is_savable(e.key()))
+        {
+            writeln!(s, "{}\t{}\t{}", BAN_HISTORY_TOKEN, e.key(), e.value()).unwrap();
+        }
+        for e in self
+            .banned_cidrs
+            .iter()
+            .filter(|e| !self.configured_cidrs.contains(e.key()))
+        {
+            writeln!(s, "{}\t{}\t{}", BAN_CIDR, e.key(), e.value()).unwrap();
+        }
+        for e in self.ban_history_cidrs.iter() {
+            writeln!(s, "{}\t{}\t{}", BAN_HISTORY_CIDR, e.key(), e.value()).unwrap();
+        }
+        let path = &self.settings.record_file;
+        let tmp = format!("{}.tmp", path);
+        if let Err(e) = std::fs::write(&tmp, s).and_then(|_| std::fs::rename(&tmp, path)) {
+            error!("Can't save guard record file '{}': {}.", path, e);
+        }
+    }
+}
+
+/// Tokens come from clients, skip the ones which would break the line format.
+fn is_savable(token: &str) -> bool {
+    !token.is_empty() && !token.contains(|c: char| c.is_control())
+}
+
+#[test]
+fn test() {
+    use crate::settings::Settings;
+
+    let mut settings = Settings::default();
+    let path = std::env::temp_dir().join(format!("guard_record_test_{}", std::process::id()));
+    settings.guard.record_file = path.to_string_lossy().into_owned();
+    let now = Local::now().timestamp();
+    let lines = [
+        format!("{}\t10.0.0.1\t{}\t3", KICK_IP, now),
+        // Out of the kick window.
+        format!("{}\t10.0.0.1\t{}\t1", KICK_IP, now - 48 * 3600),
+        format!("{}\ttoken\t{}\t2", KICK_TOKEN, now),
+        format!("{}\t10.0.0.2\t{}", BAN_IP, now + 3600),
+        // Expired.
+        format!("{}\t10.0.0.3\t{}", BAN_IP, now - 1),
+        format!("{}\ttoken\t{}", BAN_TOKEN, now + 3600),
+        format!("{}\t10.1.0.0/16\t{}", BAN_CIDR, now + 3600),
+        format!("{}\t10.0.0.2\t2", BAN_HISTORY_IP),
+        format!("{}\ttoken\t1", BAN_HISTORY_TOKEN),
+        format!("{}\t10.1.0.0/16\t3", BAN_HISTORY_CIDR),
+        // Malformed.
+        format!("{}\tnot an ip\t{}", BAN_IP, now + 3600),
+        format!("{}\t10.0.0.4", BAN_IP),
+        format!("{}\t10.0.0.5\t{}", KICK_IP, now),
+        format!("{}\t10.2.0.0/33\t{}", BAN_CIDR, now + 3600),
+        format!("unknown\t10.0.0.6\t{}", now + 3600),
+    ];
+    std::fs::write(&path, lines.join("\n")).unwrap();
+    let guard = Guard::new(&settings);
+    let ip = |s| IpAddr::from_str(s).unwrap();
+    assert_eq!(guard.kicked_ips.len(), 1);
+    assert_eq!(guard.kicked_ips.get(&ip("10.0.0.1")).unwrap().len(), 1);
+    assert_eq!(guard.kicked_tokens.get("token").unwrap().len(), 1);
+    assert_eq!(guard.banned_ips.len(), 1);
+    assert!(!guard.check_addr(&ip("10.0.0.2")));
+    assert!(guard.check_addr(&ip("10.0.0.3")));
+    assert!(!guard.check_addr(&ip("10.1.2.3")));
+    assert!(!guard.check_token(&"token".to_owned()));
+    assert_eq!(*guard.ban_history_ips.get(&ip("10.0.0.2")).unwrap(), 2);
+
+    // Reading back what was written gives the same records.
+    guard.save();
+    let loaded = Guard::new(&settings);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.kicked_ips.len(), 1);
+    assert_eq!(loaded.kicked_tokens.len(), 1);
+    assert_eq!(loaded.banned_ips.len(), 1);
+    assert_eq!(loaded.banned_tokens.len(), 1);
+    assert_eq!(loaded.banned_cidrs.len(), 1);
+    assert_eq!(loaded.ban_history_ips.len(), 1);
+    assert_eq!(loaded.ban_history_tokens.len(), 1);
+    assert_eq!(
+        *loaded
+            .ban_history_cidrs
+            .get(&Cidr::from_str("10.1.0.0/16").unwrap())
+            .unwrap(),
+        3
+    );
+}
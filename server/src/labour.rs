@@ -1,16 +1,23 @@
 use crate::guard::reason;
+use crate::labour::message::{
+    AssignTask, DeliverNotification, DeliverRelay, DeliverReport, Revalidate, Sack, UpdateRateLimit,
+};
 use crate::labour::structs::{ConnectionInfo, State};
+use crate::packet::constants::show_identity::category;
 use crate::packet::structs::VarInt;
-use crate::packet::{constants::id, PacketData};
-use crate::settings::{RateLimit, Settings};
+use crate::packet::{constants::id, Packet, PacketData, TaskChange, ToPacket};
+use crate::rate_limit::{is_looser, keeps_timeouts};
+use crate::settings::RateLimit;
 use crate::util::timer::Timer;
-use crate::{packet, GUARD};
-use actix::{Actor, ActorContext, AsyncContext, Running, SpawnHandle, StreamHandler};
-use actix_web::web::{Buf, Bytes};
+use crate::{packet, report, GUARD, PEERS, RATE_LIMITS, ROSTER, SCHEDULER, TOKENS};
+use actix::{Actor, ActorContext, AsyncContext, Handler, Running, SpawnHandle, StreamHandler};
+use actix_http::ws::Item;
+use actix_web::web::{Buf, Bytes, BytesMut};
 use actix_web_actors::ws;
 use actix_web_actors::ws::{CloseCode, CloseReason};
 use chrono::Local;
 use dashmap::DashMap;
+use fixedbitset::FixedBitSet;
 use governor::state::{InMemoryState, NotKeyed};
 use governor::{clock, Quota, RateLimiter};
 use log::info;
@@ -20,50 +27,161 @@ use std::lazy::SyncLazy;
 use std::num::NonZeroU32;
 use std::time::Duration;
 
+pub mod admin;
 pub mod handle;
+pub mod message;
+pub mod roster;
 pub mod structs;
 
-static HANDLE_MAP: SyncLazy<
-    HashMap<VarInt, fn(&mut Labour, &mut Bytes, &mut ws::WebsocketContext<Labour>)>,
-> = SyncLazy::new(|| {
-    let mut m: HashMap<VarInt, fn(&mut Labour, &mut Bytes, &mut ws::WebsocketContext<Labour>)> =
-        HashMap::new();
-    m.insert(id::SHOW_IDENTITY, handle::show_identity);
-    m.insert(id::RATE_LIMIT, handle::rate_limit);
-    m.insert(id::TASK_APPLICATION, handle::task_application);
-    m.insert(id::TASK_CHANGE, handle::task_change);
-    m.insert(id::TASK_CONFIRM, handle::task_confirm);
-    m.insert(id::NOTIFICATION, handle::notification);
+/// Maximum size of a message reassembled from continuation frames.
+const MAX_MESSAGE_SIZE: usize = 65_536;
+
+type Handle = fn(&mut Labour, &mut Bytes, &mut ws::WebsocketContext<Labour>);
+
+/// Categories of a labour which has shown its identity, `None` is one which has not.
+const IDENTIFIED: &[Option<VarInt>] = &[
+    Some(category::CLIENT),
+    Some(category::SERVER),
+    Some(category::ADMIN),
+];
+
+const CLIENTS: &[Option<VarInt>] = &[Some(category::CLIENT)];
+
+const SERVERS: &[Option<VarInt>] = &[Some(category::SERVER)];
+
+const ADMINS: &[Option<VarInt>] = &[Some(category::ADMIN)];
+
+/// The states and categories in which a labour may send a packet. Packets which
+/// only the server sends have no handle.
+struct Route {
+    states: &'static [State],
+    categories: &'static [Option<VarInt>],
+    handle: Option<Handle>,
+}
+
+impl Route {
+    fn new(
+        states: &'static [State],
+        categories: &'static [Option<VarInt>],
+        handle: Handle,
+    ) -> Route {
+        Route {
+            states,
+            categories,
+            handle: Some(handle),
+        }
+    }
+
+    fn server_only() -> Route {
+        Route {
+            states: &[],
+            categories: &[],
+            handle: None,
+        }
+    }
+
+    fn allows(&self, labour: &Labour) -> bool {
+        self.handle.is_some()
+            && self.states.contains(&labour.state)
+            && self.categories.contains(&labour.category)
+    }
+}
+
+static HANDLE_MAP: SyncLazy<HashMap<VarInt, Route>> = SyncLazy::new(|| {
+    use State::{Handshaking, Working};
+    let mut m = HashMap::new();
+    m.insert(
+        id::SHOW_IDENTITY,
+        Route::new(&[Handshaking], &[None], handle::show_identity),
+    );
+    m.insert(id::RATE_LIMIT, Route::server_only());
+    m.insert(
+        id::TASK_APPLICATION,
+        Route::new(&[Handshaking, Working], CLIENTS, handle::task_application),
+    );
+    m.insert(id::TASK_CHANGE, Route::server_only());
+    m.insert(
+        id::TASK_CONFIRM,
+        Route::new(&[Handshaking, Working], CLIENTS, handle::task_confirm),
+    );
+    m.insert(
+        id::DATA_REPORT,
+        Route::new(&[Working], IDENTIFIED, handle::data_report),
+    );
+    m.insert(
+        id::RELAY_REPORT,
+        Route::new(&[Working], SERVERS, handle::relay_report),
+    );
+    m.insert(
+        id::SERVER_ID,
+        Route::new(&[Working], SERVERS, handle::server_id),
+    );
+    m.insert(
+        id::LIST_LABOURS,
+        Route::new(&[Working], ADMINS, admin::list_labours),
+    );
+    m.insert(id::LABOUR_LIST, Route::server_only());
+    m.insert(id::ADMIN_KICK, Route::new(&[Working], ADMINS, admin::kick));
+    m.insert(id::ADMIN_BAN, Route::new(&[Working], ADMINS, admin::ban));
+    m.insert(
+        id::ADMIN_RATE_LIMIT,
+        Route::new(&[Working], ADMINS, admin::rate_limit),
+    );
+    m.insert(
+        id::ADMIN_ROOMS,
+        Route::new(&[Working], ADMINS, admin::rooms),
+    );
+    m.insert(id::GET_STATS, Route::new(&[Working], ADMINS, admin::stats));
+    m.insert(id::STATS, Route::server_only());
+    m.insert(id::ADMIN_RESULT, Route::server_only());
+    m.insert(
+        id::SUBSCRIPTION,
+        Route::new(&[Handshaking, Working], IDENTIFIED, handle::subscription),
+    );
+    m.insert(
+        id::NOTIFICATION,
+        Route::new(&[Handshaking, Working], IDENTIFIED, handle::notification),
+    );
     m
 });
 
 pub struct Labour {
+    pub id: u64,
     pub connection_info: ConnectionInfo,
     pub category: Option<VarInt>,
     pub token: String,
     state: State,
+    subscriptions: FixedBitSet,
     rate_limit: RateLimit,
     rate_limiter: RateLimiter<NotKeyed, InMemoryState, clock::DefaultClock>,
+    /// A stricter rate limit which has been sent but not applied yet.
+    pending_rate_limit: Option<SpawnHandle>,
     response_ids: HashSet<VarInt>,
+    /// Whether the fragmented message is text, and the fragments received so far.
+    continuation: Option<(bool, BytesMut)>,
+    /// Closed by a kick, a ban or a sack, so its rooms are not kept for it.
+    dismissed: bool,
     response_timer: Timer<Self, ws::WebsocketContext<Self>>,
     heartbeat_timer: Timer<Self, ws::WebsocketContext<Self>>,
 }
 
 impl Labour {
-    pub fn new(connection_info: ConnectionInfo, settings: &Settings) -> Labour {
-        let quota = Quota::with_period(Duration::from_millis(settings.rate_limit.interval as u64))
-            .unwrap()
-            .allow_burst(NonZeroU32::new(settings.rate_limit.max_burst as u32).unwrap());
+    pub fn new(connection_info: ConnectionInfo) -> Labour {
+        let rate_limit = RATE_LIMITS.global();
         Labour {
+            id: ROSTER.next_id(),
             connection_info,
             category: None,
             token: String::new(),
             state: State::Handshaking,
-            rate_limit: settings.rate_limit.clone(),
-            rate_limiter: RateLimiter::direct(quota),
+            subscriptions: report::subscription(&report::CATEGORIES).unwrap(),
+            rate_limiter: RateLimiter::direct(quota(&rate_limit)),
+            pending_rate_limit: None,
             response_ids: HashSet::new(),
+            continuation: None,
+            dismissed: false,
             response_timer: Timer::new(
-                Duration::from_millis((settings.rate_limit.interval * 2) as u64),
+                Duration::from_millis(rate_limit.interval as u64 * 2),
                 |labour, ctx| {
                     info!(
                         "No response packet received from Labour '{}' for {}s.",
@@ -74,9 +192,7 @@ impl Labour {
                 },
             ),
             heartbeat_timer: Timer::new(
-                Duration::from_millis(
-                    (settings.rate_limit.interval * settings.rate_limit.max_burst) as u64,
-                ),
+                Duration::from_millis(rate_limit.interval as u64 * rate_limit.max_burst as u64),
                 |labour, ctx| {
                     info!(
                         "No heartbeat packet received from Labour '{}' for {}s.",
@@ -86,6 +202,7 @@ impl Labour {
                     labour.kick(ctx, reason::kick::Reason::HeartbeatTimeout);
                 },
             ),
+            rate_limit,
         }
     }
 
@@ -97,21 +214,128 @@ impl Labour {
 
     #[inline]
     pub fn sack(&mut self, ctx: &mut ws::WebsocketContext<Self>, reason: Option<CloseReason>) {
+        self.dismissed = true;
         self.stop_timer(ctx);
         GUARD.sack(ctx, reason);
     }
 
     #[inline]
     pub fn kick(&mut self, ctx: &mut ws::WebsocketContext<Self>, reason: reason::kick::Reason) {
+        self.dismissed = true;
         self.stop_timer(ctx);
         GUARD.kick(&self, ctx, reason);
     }
 
     #[inline]
     pub fn ban(&mut self, ctx: &mut ws::WebsocketContext<Self>, reason: reason::ban::Reason) {
+        self.dismissed = true;
         self.stop_timer(ctx);
         GUARD.ban(&self, ctx, reason);
     }
+
+    /// Send a packet which the labour has to answer with `response_id` in time.
+    pub fn request(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        packet: Packet,
+        response_id: VarInt,
+    ) {
+        ctx.binary(packet.to_bytes());
+        self.response_ids.insert(response_id);
+        self.response_timer.start(ctx);
+    }
+
+    pub fn assign(&mut self, ctx: &mut ws::WebsocketContext<Self>, room_ids: Vec<String>) {
+        info!(
+            "Assign {} rooms to Labour '{}'.",
+            room_ids.len(),
+            self.token
+        );
+        self.request(
+            ctx,
+            TaskChange {
+                room_count: room_ids.len() as VarInt,
+                room_ids,
+            }
+            .to_packet(),
+            id::TASK_CONFIRM,
+        );
+    }
+
+    pub fn send_rate_limit(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.binary(
+            packet::RateLimit {
+                interval: self.rate_limit.interval as VarInt,
+                max_burst: self.rate_limit.max_burst as VarInt,
+            }
+            .to_packet()
+            .to_bytes(),
+        );
+    }
+
+    /// Rebuild the rate limiter and the timers from `rate_limit`.
+    pub fn apply_rate_limit(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        if let Some(handle) = self.pending_rate_limit.take() {
+            ctx.cancel_future(handle);
+        }
+        self.rate_limiter = RateLimiter::direct(quota(&self.rate_limit));
+        self.response_timer.duration = Duration::from_millis(self.rate_limit.interval as u64 * 2);
+        self.heartbeat_timer.duration = Duration::from_millis(
+            self.rate_limit.interval as u64 * self.rate_limit.max_burst as u64,
+        );
+        if !self.response_ids.is_empty() {
+            self.response_timer.start(ctx);
+        }
+        self.heartbeat_timer.start(ctx);
+    }
+
+    /// Send a changed rate limit to the labour. A looser one which keeps both
+    /// timeouts applies at once. Otherwise the timers, and the rate limiter unless
+    /// it is looser, change only after the response timeout, so the client can
+    /// adapt first.
+    pub fn update_rate_limit(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        rate_limit: RateLimit,
+    ) {
+        if rate_limit == self.rate_limit {
+            return;
+        }
+        info!(
+            "Rate limit of Labour '{}' changes to {}ms with a burst of {}.",
+            self.token, rate_limit.interval, rate_limit.max_burst
+        );
+        let old = std::mem::replace(&mut self.rate_limit, rate_limit);
+        self.send_rate_limit(ctx);
+        if is_looser(&self.rate_limit, &old) {
+            if keeps_timeouts(&self.rate_limit, &old) {
+                self.apply_rate_limit(ctx);
+                return;
+            }
+            self.rate_limiter = RateLimiter::direct(quota(&self.rate_limit));
+        }
+        if let Some(handle) = self.pending_rate_limit.take() {
+            ctx.cancel_future(handle);
+        }
+        self.pending_rate_limit =
+            Some(ctx.run_later(self.response_timer.duration, |labour, ctx| {
+                labour.pending_rate_limit = None;
+                labour.apply_rate_limit(ctx);
+            }));
+    }
+
+    /// Apply changed assignments, sending the ones of other labours through the roster.
+    pub fn dispatch(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        mut changes: Vec<(u64, Vec<String>)>,
+    ) {
+        if let Some(i) = changes.iter().position(|(id, _)| *id == self.id) {
+            let (_, room_ids) = changes.remove(i);
+            self.assign(ctx, room_ids);
+        }
+        ROSTER.assign(changes);
+    }
 }
 
 impl Actor for Labour {
@@ -119,6 +343,11 @@ impl Actor for Labour {
 
     #[inline]
     fn started(&mut self, ctx: &mut Self::Context) {
+        ROSTER.employ(self.id, self.connection_info.client_ip, ctx.address());
+        if !GUARD.check_connections(&self.connection_info.client_ip) {
+            self.kick(ctx, reason::kick::Reason::TooManyConnections);
+            return;
+        }
         self.response_ids.insert(id::SHOW_IDENTITY);
         self.response_timer.start(ctx);
         self.heartbeat_timer.start(ctx);
@@ -130,43 +359,217 @@ impl Actor for Labour {
     }
 
     #[inline]
-    fn stopped(&mut self, ctx: &mut Self::Context) {}
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        ROSTER.dismiss(self.id);
+        // Rooms wait for a dropped labour no longer than a response timeout, and
+        // not at all for one which was closed by the server.
+        let grace = if self.dismissed {
+            Duration::from_secs(0)
+        } else {
+            self.response_timer.duration
+        };
+        ROSTER.assign(SCHEDULER.release(self.id, &self.token, grace));
+        if self.category == Some(category::SERVER) {
+            PEERS.leave(self.id);
+        }
+    }
+}
+
+impl Handler<Sack> for Labour {
+    type Result = ();
+
+    fn handle(&mut self, msg: Sack, ctx: &mut Self::Context) {
+        self.sack(ctx, msg.0);
+    }
+}
+
+impl Handler<AssignTask> for Labour {
+    type Result = ();
+
+    fn handle(&mut self, msg: AssignTask, ctx: &mut Self::Context) {
+        if ctx.state().alive() {
+            self.assign(ctx, msg.0);
+        }
+    }
+}
+
+impl Handler<DeliverReport> for Labour {
+    type Result = ();
+
+    fn handle(&mut self, msg: DeliverReport, ctx: &mut Self::Context) {
+        if msg.from != self.id
+            && self.state == State::Working
+            && self.category != Some(category::SERVER)
+            && self.subscriptions.contains(msg.category as usize)
+            && ctx.state().alive()
+        {
+            ctx.binary(msg.data);
+        }
+    }
+}
+
+impl Handler<DeliverRelay> for Labour {
+    type Result = ();
+
+    fn handle(&mut self, msg: DeliverRelay, ctx: &mut Self::Context) {
+        if self.state == State::Working && ctx.state().alive() {
+            ctx.binary(msg.0);
+        }
+    }
+}
+
+impl Handler<DeliverNotification> for Labour {
+    type Result = ();
+
+    fn handle(&mut self, msg: DeliverNotification, ctx: &mut Self::Context) {
+        if msg.from != self.id
+            && self.category.is_some()
+            && (msg.category == 0 || self.category == Some(msg.category))
+            && ctx.state().alive()
+        {
+            ctx.binary(msg.data);
+        }
+    }
+}
+
+impl Handler<UpdateRateLimit> for Labour {
+    type Result = ();
+
+    fn handle(&mut self, msg: UpdateRateLimit, ctx: &mut Self::Context) {
+        if self.category.is_some() && ctx.state().alive() {
+            self.update_rate_limit(ctx, RATE_LIMITS.get(self.category, &self.token));
+        }
+    }
+}
+
+impl Handler<Revalidate> for Labour {
+    type Result = ();
+
+    fn handle(&mut self, msg: Revalidate, ctx: &mut Self::Context) {
+        if let Some(category) = self.category {
+            if !TOKENS.check(category, &self.token) {
+                info!("Token of Labour '{}' has been revoked.", self.token);
+                let reason = reason::sack::CODE_MAP
+                    .get(&reason::sack::Reason::TokenRevoked)
+                    .unwrap()
+                    .value()
+                    .clone();
+                self.sack(ctx, Some(reason));
+            }
+        }
+    }
 }
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Labour {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-        if self.rate_limiter.check().is_err() {
+        // Fragments after the first one and control frames without effect are not counted.
+        let counted = !matches!(
+            msg,
+            Ok(ws::Message::Close(_))
+                | Ok(ws::Message::Nop)
+                | Ok(ws::Message::Continuation(Item::Continue(_)))
+                | Ok(ws::Message::Continuation(Item::Last(_)))
+        );
+        if counted && self.rate_limiter.check().is_err() {
             self.kick(ctx, reason::kick::Reason::RateLimit);
             return;
         }
         match msg {
-            Ok(ws::Message::Binary(mut bin)) => {
+            Ok(ws::Message::Binary(bin)) => self.receive_binary(ctx, bin),
+            Ok(ws::Message::Text(text)) => self.receive_text(ctx, text.as_bytes()),
+            Ok(ws::Message::Continuation(item)) => self.receive_continuation(ctx, item),
+            Ok(ws::Message::Ping(bytes)) => {
                 self.heartbeat_timer.start(ctx);
-                while bin.has_remaining() {
-                    if let Some(mut pkt) = packet::Packet::read_from_bytes(&mut bin) {
-                        println!("{:?} !! {:?}", pkt, bin);
-                        if let Some(val) = HANDLE_MAP.get(&pkt.id) {
-                            if self.response_ids.contains(&pkt.id) {
-                                self.response_timer.stop(ctx);
-                                self.response_ids.remove(&pkt.id);
-                            }
-                            val(self, &mut pkt.data, ctx);
-                            if ctx.state().alive() {
-                                continue;
-                            } else {
-                                break;
-                            }
-                        }
+                ctx.pong(&bytes);
+            }
+            Ok(ws::Message::Pong(_)) => self.heartbeat_timer.start(ctx),
+            Ok(ws::Message::Close(_)) | Ok(ws::Message::Nop) => {}
+            Err(e) => {
+                info!("Protocol error from Labour '{}': {}.", self.token, e);
+                self.kick(ctx, reason::kick::Reason::IncorrectDataFormat);
+            }
+        }
+    }
+}
+
+impl Labour {
+    fn receive_binary(&mut self, ctx: &mut ws::WebsocketContext<Self>, mut bin: Bytes) {
+        self.heartbeat_timer.start(ctx);
+        while bin.has_remaining() {
+            if let Some(mut pkt) = packet::Packet::read_from_bytes(&mut bin) {
+                if let Some(route) = HANDLE_MAP.get(&pkt.id) {
+                    if !route.allows(self) {
+                        info!(
+                            "Labour '{}' sends packet {:#04x} which is not allowed in {:?}.",
+                            self.token, pkt.id, self.state
+                        );
+                        self.kick(ctx, reason::kick::Reason::UnexpectedPacket);
+                        return;
+                    }
+                    if self.response_ids.contains(&pkt.id) {
+                        self.response_timer.stop(ctx);
+                        self.response_ids.remove(&pkt.id);
+                    }
+                    (route.handle.unwrap())(self, &mut pkt.data, ctx);
+                    if ctx.state().alive() {
+                        continue;
+                    } else {
+                        break;
                     }
-                    self.kick(ctx, reason::kick::Reason::InvalidPacket);
-                    return;
                 }
             }
-            Ok(ws::Message::Close(reason)) => return,
-            _ => self.kick(ctx, reason::kick::Reason::IncorrectDataFormat),
+            self.kick(ctx, reason::kick::Reason::InvalidPacket);
+            return;
+        }
+    }
+
+    /// The protocol is binary, only an empty text message is taken as a heartbeat.
+    fn receive_text(&mut self, ctx: &mut ws::WebsocketContext<Self>, text: &[u8]) {
+        if text.is_empty() {
+            self.heartbeat_timer.start(ctx);
+        } else {
+            self.kick(ctx, reason::kick::Reason::IncorrectDataFormat);
+        }
+    }
+
+    /// Collect fragments until the last one, then handle them as one message.
+    fn receive_continuation(&mut self, ctx: &mut ws::WebsocketContext<Self>, item: Item) {
+        let (first, text, data, last) = match item {
+            Item::FirstText(data) => (true, true, data, false),
+            Item::FirstBinary(data) => (true, false, data, false),
+            Item::Continue(data) => (false, false, data, false),
+            Item::Last(data) => (false, false, data, true),
+        };
+        if first == self.continuation.is_some() {
+            self.continuation = None;
+            self.kick(ctx, reason::kick::Reason::IncorrectDataFormat);
+            return;
+        }
+        let (_, buf) = self
+            .continuation
+            .get_or_insert_with(|| (text, BytesMut::new()));
+        if buf.len() + data.len() > MAX_MESSAGE_SIZE {
+            self.continuation = None;
+            self.kick(ctx, reason::kick::Reason::IncorrectDataFormat);
+            return;
+        }
+        buf.extend_from_slice(&data);
+        if last {
+            let (text, buf) = self.continuation.take().unwrap();
+            if text {
+                self.receive_text(ctx, &buf);
+            } else {
+                self.receive_binary(ctx, buf.freeze());
+            }
         }
     }
 }
 
+fn quota(rate_limit: &RateLimit) -> Quota {
+    Quota::with_period(Duration::from_millis(rate_limit.interval as u64))
+        .unwrap()
+        .allow_burst(NonZeroU32::new(rate_limit.max_burst).unwrap())
+}
+
 #[test]
 fn test() {}
@@ -0,0 +1,196 @@
+use crate::guard::{format_time, reason, Target};
+use crate::labour::message::UpdateRateLimit;
+use crate::labour::Labour;
+use crate::packet::constants::{admin_rate_limit::scope, admin_result::code, admin_rooms::action};
+use crate::packet::structs::VarInt;
+use crate::packet::{
+    constants::id, AdminBan, AdminKick, AdminRateLimit, AdminResult, AdminRooms, GetStats,
+    LabourInfo, LabourList, ListLabours, PacketData, Stats, ToPacket,
+};
+use crate::rate_limit::{is_valid, MAX_HEARTBEAT_TIMEOUT};
+use crate::settings::RateLimit;
+use crate::{GUARD, PEERS, RATE_LIMITS, REPORTS, ROSTER, SCHEDULER};
+use actix_web::web::Bytes;
+use actix_web_actors::ws::WebsocketContext;
+use log::info;
+use std::str::FromStr;
+
+/// Answer a request of an admin with the outcome.
+fn reply(ctx: &mut WebsocketContext<Labour>, request_id: VarInt, result: Result<String, String>) {
+    let (code, message) = match result {
+        Ok(message) => (code::OK, message),
+        Err(message) => (code::FAILED, message),
+    };
+    ctx.binary(
+        AdminResult {
+            request_id,
+            code,
+            message,
+        }
+        .to_packet()
+        .to_bytes(),
+    );
+}
+
+pub fn list_labours(labour: &mut Labour, data: &mut Bytes, ctx: &mut WebsocketContext<Labour>) {
+    if ListLabours::read_from_bytes(data).is_some() {
+        let labours: Vec<LabourInfo> = ROSTER
+            .members()
+            .into_iter()
+            .map(|m| LabourInfo {
+                category: m.category.unwrap_or(0),
+                token: m.token.unwrap_or_default(),
+                ip: m.ip.to_string(),
+                room_count: SCHEDULER.assignment(m.id).len() as VarInt,
+            })
+            .collect();
+        ctx.binary(
+            LabourList {
+                labour_count: labours.len() as VarInt,
+                labours,
+            }
+            .to_packet()
+            .to_bytes(),
+        );
+        return;
+    }
+    labour.kick(ctx, reason::kick::Reason::InvalidPacket);
+}
+
+pub fn kick(labour: &mut Labour, data: &mut Bytes, ctx: &mut WebsocketContext<Labour>) {
+    if let Some(data) = AdminKick::read_from_bytes(data) {
+        let target = Target::from(data.target.as_str());
+        info!("Admin '{}' kicks {}.", labour.token, target);
+        let reason = reason::sack::CODE_MAP
+            .get(&reason::sack::Reason::KickedByAdmin)
+            .unwrap()
+            .value()
+            .clone();
+        match &target {
+            Target::Ip(ip) => ROSTER.sack_ip(ip, Some(reason)),
+            Target::Cidr(cidr) => ROSTER.sack_cidr(cidr, Some(reason)),
+            Target::Token(token) => ROSTER.sack_token(token, Some(reason)),
+        }
+        reply(ctx, id::ADMIN_KICK, Ok(format!("Kicked {}.", target)));
+        return;
+    }
+    labour.kick(ctx, reason::kick::Reason::InvalidPacket);
+}
+
+pub fn ban(labour: &mut Labour, data: &mut Bytes, ctx: &mut WebsocketContext<Labour>) {
+    if let Some(data) = AdminBan::read_from_bytes(data) {
+        let target = Target::from(data.target.as_str());
+        info!(
+            "Admin '{}' bans {} for {} hours.",
+            labour.token, target, data.hours
+        );
+        let description = if data.reason.is_empty() {
+            "banned by an admin".to_owned()
+        } else {
+            data.reason
+        };
+        let result = match GUARD.ban_target(&target, Some(data.hours as i64), &description) {
+            Some(t) => Ok(format!("Banned {} until '{}'.", target, format_time(t))),
+            None => Err(format!("{} is on the allowlist.", target)),
+        };
+        reply(ctx, id::ADMIN_BAN, result);
+        return;
+    }
+    labour.kick(ctx, reason::kick::Reason::InvalidPacket);
+}
+
+pub fn rate_limit(labour: &mut Labour, data: &mut Bytes, ctx: &mut WebsocketContext<Labour>) {
+    if let Some(data) = AdminRateLimit::read_from_bytes(data) {
+        // Zero falls back to the less specific rate limit.
+        let rate_limit = if data.interval > 0 && data.max_burst > 0 {
+            Some(RateLimit {
+                interval: data.interval,
+                max_burst: data.max_burst,
+            })
+        } else {
+            None
+        };
+        let result = match (data.scope, rate_limit) {
+            (_, Some(rate_limit)) if !is_valid(&rate_limit) => Err(format!(
+                "The heartbeat timeout can't exceed {}ms.",
+                MAX_HEARTBEAT_TIMEOUT
+            )),
+            (scope::GLOBAL, Some(rate_limit)) => {
+                RATE_LIMITS.set_global(rate_limit);
+                Ok("Changed the global rate limit.".to_owned())
+            }
+            (scope::CATEGORY, rate_limit) => match VarInt::from_str(&data.key) {
+                Ok(category) => {
+                    RATE_LIMITS.set_category(category, rate_limit);
+                    Ok(format!("Changed the rate limit of category {}.", category))
+                }
+                Err(_) => Err(format!("Invalid category '{}'.", data.key)),
+            },
+            (scope::TOKEN, rate_limit) => {
+                RATE_LIMITS.set_token(&data.key, rate_limit);
+                Ok(format!("Changed the rate limit of token '{}'.", data.key))
+            }
+            _ => Err("Invalid rate limit.".to_owned()),
+        };
+        if result.is_ok() {
+            info!(
+                "Admin '{}' changes the rate limit of scope {} '{}' to {}ms with a burst of {}.",
+                labour.token, data.scope, data.key, data.interval, data.max_burst
+            );
+            ROSTER.broadcast(UpdateRateLimit);
+        }
+        reply(ctx, id::ADMIN_RATE_LIMIT, result);
+        return;
+    }
+    labour.kick(ctx, reason::kick::Reason::InvalidPacket);
+}
+
+pub fn rooms(labour: &mut Labour, data: &mut Bytes, ctx: &mut WebsocketContext<Labour>) {
+    if let Some(data) = AdminRooms::read_from_bytes(data) {
+        let room_ids: Vec<String> = data
+            .room_ids
+            .into_iter()
+            .filter(|room| !room.is_empty())
+            .collect();
+        let result = match data.action {
+            action::ADD => {
+                let (n, changes) = SCHEDULER.add_rooms(&room_ids);
+                ROSTER.assign(changes);
+                Ok(format!("Added {} rooms to the pool.", n))
+            }
+            action::REMOVE => {
+                let (n, changes) = SCHEDULER.remove_rooms(&room_ids);
+                ROSTER.assign(changes);
+                Ok(format!("Removed {} rooms from the pool.", n))
+            }
+            _ => Err(format!("Invalid action {}.", data.action)),
+        };
+        if let Ok(message) = &result {
+            info!("Admin '{}': {}", labour.token, message);
+        }
+        reply(ctx, id::ADMIN_ROOMS, result);
+        return;
+    }
+    labour.kick(ctx, reason::kick::Reason::InvalidPacket);
+}
+
+pub fn stats(labour: &mut Labour, data: &mut Bytes, ctx: &mut WebsocketContext<Labour>) {
+    if GetStats::read_from_bytes(data).is_some() {
+        let (rooms, owned, assigned) = SCHEDULER.counts();
+        ctx.binary(
+            Stats {
+                labour_count: ROSTER.len() as VarInt,
+                server_count: PEERS.server_count() as VarInt + 1,
+                room_count: rooms as VarInt,
+                owned_room_count: owned as VarInt,
+                assigned_room_count: assigned as VarInt,
+                report_count: REPORTS.len() as VarInt,
+                ban_count: GUARD.bans().len() as VarInt,
+            }
+            .to_packet()
+            .to_bytes(),
+        );
+        return;
+    }
+    labour.kick(ctx, reason::kick::Reason::InvalidPacket);
+}
@@ -1,46 +1,234 @@
 use crate::guard::reason;
+use crate::labour::message::{DeliverNotification, DeliverReport};
 use crate::labour::structs::State;
 use crate::labour::Labour;
+use crate::packet::constants::show_identity::category;
 use crate::packet::structs::VarInt;
-use crate::packet::{Packet, PacketData, RateLimit, ShowIdentity, ToPacket};
+use crate::packet::{
+    DataReport, Notification, Packet, PacketData, RelayReport, ServerId, ShowIdentity,
+    Subscription, TaskApplication, TaskConfirm, ToPacket,
+};
+use crate::{federation, report};
+use crate::{GUARD, PEERS, RATE_LIMITS, REPORTS, ROSTER, SCHEDULER, SETTINGS, TOKENS};
 use actix::Actor;
 use actix_web::web::{Bytes, BytesMut};
 use actix_web_actors::ws;
 use actix_web_actors::ws::WebsocketContext;
-use log::info;
+use log::{debug, info, warn};
 use std::collections::HashMap;
 use std::lazy::SyncLazy;
 
 pub fn show_identity(labour: &mut Labour, data: &mut Bytes, ctx: &mut WebsocketContext<Labour>) {
-    if labour.state == State::Handshaking && labour.category.is_none() {
-        if let Some(data) = ShowIdentity::read_from_bytes(data) {
-            labour.category = Some(data.category);
-            labour.token = data.token;
-            info!("Labour '{}' is employed.", labour.token);
-            ctx.binary(
-                RateLimit {
-                    interval: labour.rate_limit.interval as VarInt,
-                    max_burst: labour.rate_limit.max_burst as VarInt,
-                }
-                .to_packet()
-                .to_bytes(),
+    if let Some(data) = ShowIdentity::read_from_bytes(data) {
+        if !TOKENS.check(data.category, &data.token) {
+            info!(
+                "Labour '{}' shows an invalid token for category {}.",
+                data.token, data.category
             );
+            // The token is unproven, so only the IP is banned.
+            labour.ban(ctx, reason::ban::Reason::InvalidToken);
+            return;
+        }
+        labour.category = Some(data.category);
+        labour.token = data.token;
+        if !GUARD.check_token(&labour.token) {
+            info!("Labour '{}' is banned.", labour.token);
+            let reason = reason::ban::CODE_MAP
+                .get(&reason::ban::Reason::Banned)
+                .unwrap()
+                .value()
+                .clone();
+            labour.sack(ctx, Some(reason));
             return;
         }
-        labour.kick(ctx, reason::kick::Reason::InvalidPacket);
+        ROSTER.bind_token(labour.id, data.category, &labour.token);
+        if !GUARD.check_token_connections(&labour.token) {
+            labour.kick(ctx, reason::kick::Reason::TooManyConnections);
+            return;
+        }
+        info!("Labour '{}' is employed.", labour.token);
+        labour.rate_limit = RATE_LIMITS.get(labour.category, &labour.token);
+        labour.apply_rate_limit(ctx);
+        labour.send_rate_limit(ctx);
+        if data.category == category::SERVER {
+            PEERS.register(labour.id);
+        }
+        // Only clients monitor rooms, the others start working at once.
+        if labour.category != Some(category::CLIENT) {
+            labour.state = State::Working;
+            info!("Labour '{}' starts working.", labour.token);
+        }
+        return;
     }
-    labour.kick(ctx, reason::kick::Reason::UnexpectedPacket);
+    labour.kick(ctx, reason::kick::Reason::InvalidPacket);
 }
 
-pub fn rate_limit(labour: &mut Labour, data: &mut Bytes, ctx: &mut WebsocketContext<Labour>) {}
-
 pub fn task_application(labour: &mut Labour, data: &mut Bytes, ctx: &mut WebsocketContext<Labour>) {
+    if let Some(data) = TaskApplication::read_from_bytes(data) {
+        if data.room_count == 0 && labour.state == State::Working {
+            return;
+        }
+        let changes = SCHEDULER.apply(labour.id, &labour.token, data.room_count as usize);
+        labour.dispatch(ctx, changes);
+        return;
+    }
+    labour.kick(ctx, reason::kick::Reason::InvalidPacket);
 }
 
-pub fn task_change(labour: &mut Labour, data: &mut Bytes, ctx: &mut WebsocketContext<Labour>) {}
+pub fn task_confirm(labour: &mut Labour, data: &mut Bytes, ctx: &mut WebsocketContext<Labour>) {
+    if let Some(data) = TaskConfirm::read_from_bytes(data) {
+        match SCHEDULER.confirm(labour.id, &labour.token, &data.room_ids) {
+            Ok(()) => {
+                if labour.state == State::Handshaking {
+                    labour.state = State::Working;
+                    info!("Labour '{}' starts working.", labour.token);
+                }
+            }
+            Err(changes) => labour.dispatch(ctx, changes),
+        }
+        return;
+    }
+    labour.kick(ctx, reason::kick::Reason::InvalidPacket);
+}
 
-pub fn task_confirm(labour: &mut Labour, data: &mut Bytes, ctx: &mut WebsocketContext<Labour>) {}
+pub fn data_report(labour: &mut Labour, data: &mut Bytes, ctx: &mut WebsocketContext<Labour>) {
+    if let Some(data) = DataReport::read_from_bytes(data) {
+        if !report::is_valid_category(data.category) {
+            labour.kick(ctx, reason::kick::Reason::InvalidPacket);
+            return;
+        }
+        publish_report(
+            labour.id,
+            &labour.token,
+            &SETTINGS.federation.server_id,
+            0,
+            &data,
+        );
+        return;
+    }
+    labour.kick(ctx, reason::kick::Reason::InvalidPacket);
+}
 
-pub fn data_report(labour: &mut Labour, data: &mut Bytes, ctx: &mut WebsocketContext<Labour>) {}
+pub fn relay_report(labour: &mut Labour, data: &mut Bytes, ctx: &mut WebsocketContext<Labour>) {
+    if let Some(data) = RelayReport::read_from_bytes(data) {
+        if !report::is_valid_category(data.report.category) {
+            labour.kick(ctx, reason::kick::Reason::InvalidPacket);
+            return;
+        }
+        federation::receive_relay(labour.id, &labour.token, &data);
+        return;
+    }
+    labour.kick(ctx, reason::kick::Reason::InvalidPacket);
+}
 
-pub fn notification(labour: &mut Labour, data: &mut Bytes, ctx: &mut WebsocketContext<Labour>) {}
+/// Record the server behind a labour and answer with the ID of this one, so that
+/// both share the rooms between them.
+pub fn server_id(labour: &mut Labour, data: &mut Bytes, ctx: &mut WebsocketContext<Labour>) {
+    if let Some(data) = ServerId::read_from_bytes(data) {
+        if data.server_id.is_empty() {
+            labour.kick(ctx, reason::kick::Reason::InvalidPacket);
+            return;
+        }
+        if data.server_id == SETTINGS.federation.server_id {
+            warn!(
+                "Labour '{}' has the same server ID as this server.",
+                labour.token
+            );
+            return;
+        }
+        info!("Labour '{}' is server '{}'.", labour.token, data.server_id);
+        ctx.binary(
+            ServerId {
+                server_id: SETTINGS.federation.server_id.clone(),
+            }
+            .to_packet()
+            .to_bytes(),
+        );
+        PEERS.join(labour.id, data.server_id);
+        return;
+    }
+    labour.kick(ctx, reason::kick::Reason::InvalidPacket);
+}
+
+/// Deliver a new report to every labour and peer server except the one it came from.
+/// `origin` is the server where it was first reported and `hops` the number of
+/// servers it has been relayed through.
+pub fn publish_report(
+    from: u64,
+    reporter: &str,
+    origin: &str,
+    hops: VarInt,
+    data: &DataReport,
+) -> report::Check {
+    let check = REPORTS.check(data);
+    match check {
+        report::Check::New => {
+            info!(
+                "Labour '{}' reports {} '{}' in room '{}'.",
+                reporter, data.category, data.id, data.room_id
+            );
+            ROSTER.broadcast(DeliverReport {
+                from,
+                category: data.category,
+                data: data.to_packet().to_bytes(),
+            });
+            if hops < SETTINGS.federation.max_hops {
+                let relay = RelayReport {
+                    origin: origin.to_owned(),
+                    hops: hops + 1,
+                    report: data.clone(),
+                };
+                PEERS.relay(from, origin, relay.to_packet().to_bytes());
+            }
+        }
+        check => debug!(
+            "Drop {:?} report {} '{}' in room '{}' from Labour '{}'.",
+            check, data.category, data.id, data.room_id, reporter
+        ),
+    }
+    check
+}
+
+pub fn subscription(labour: &mut Labour, data: &mut Bytes, ctx: &mut WebsocketContext<Labour>) {
+    if let Some(data) = Subscription::read_from_bytes(data) {
+        if let Some(subscriptions) = report::subscription(&data.categories) {
+            info!(
+                "Labour '{}' subscribes to {:?}.",
+                labour.token, data.categories
+            );
+            labour.subscriptions = subscriptions;
+            return;
+        }
+    }
+    labour.kick(ctx, reason::kick::Reason::InvalidPacket);
+}
+
+/// Broadcast a notification signed with an admin token, without the token.
+pub fn notification(labour: &mut Labour, data: &mut Bytes, ctx: &mut WebsocketContext<Labour>) {
+    if let Some(data) = Notification::read_from_bytes(data) {
+        if !TOKENS.check(category::ADMIN, &data.token) {
+            info!(
+                "Labour '{}' sends a notification with an invalid token.",
+                labour.token
+            );
+            labour.kick(ctx, reason::kick::Reason::Unauthorized);
+            return;
+        }
+        info!(
+            "Labour '{}' notifies category {}: {}",
+            labour.token, data.category, data.message
+        );
+        let category = data.category;
+        let data = Notification {
+            token: String::new(),
+            ..data
+        };
+        ROSTER.broadcast(DeliverNotification {
+            from: labour.id,
+            category,
+            data: data.to_packet().to_bytes(),
+        });
+        return;
+    }
+    labour.kick(ctx, reason::kick::Reason::InvalidPacket);
+}
@@ -0,0 +1,69 @@
+use crate::packet::structs::VarInt;
+use actix::Message;
+use actix_web::web::Bytes;
+use actix_web_actors::ws::CloseReason;
+
+/// Close the connection without recording anything in the guard.
+#[derive(Debug, Clone)]
+pub struct Sack(pub Option<CloseReason>);
+
+impl Message for Sack {
+    type Result = ();
+}
+
+/// Check the token again after the token files have been reloaded.
+#[derive(Debug, Clone)]
+pub struct Revalidate;
+
+impl Message for Revalidate {
+    type Result = ();
+}
+
+/// Look up the rate limit of the labour again after it has been changed at runtime.
+#[derive(Debug, Clone)]
+pub struct UpdateRateLimit;
+
+impl Message for UpdateRateLimit {
+    type Result = ();
+}
+
+/// Send a Task Change with the new rooms and wait for the Task Confirm.
+#[derive(Debug, Clone)]
+pub struct AssignTask(pub Vec<String>);
+
+impl Message for AssignTask {
+    type Result = ();
+}
+
+/// Forward an encoded Data Report packet to a working labour other than its reporter.
+/// Servers get a Deliver Relay instead.
+#[derive(Debug, Clone)]
+pub struct DeliverReport {
+    pub from: u64,
+    pub category: VarInt,
+    pub data: Bytes,
+}
+
+impl Message for DeliverReport {
+    type Result = ();
+}
+
+/// Forward an encoded Relay Report packet to a server.
+#[derive(Debug, Clone)]
+pub struct DeliverRelay(pub Bytes);
+
+impl Message for DeliverRelay {
+    type Result = ();
+}
+
+/// Forward an encoded Notification packet to the labours of a category, 0 for all.
+#[derive(Debug, Clone)]
+pub struct DeliverNotification {
+    pub from: u64,
+    pub category: VarInt,
+    pub data: Bytes,
+}
+
+impl Message for DeliverNotification {
+    type Result = ();
+}
@@ -0,0 +1,222 @@
+use crate::guard::cidr::Cidr;
+use crate::labour::message::{AssignTask, Sack};
+use crate::labour::Labour;
+use crate::packet::structs::VarInt;
+use crate::util::subnet;
+use crate::GUARD;
+use actix::{Addr, Handler, Message};
+use actix_web_actors::ws::CloseReason;
+use dashmap::DashMap;
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug)]
+struct Entry {
+    addr: Addr<Labour>,
+    ip: IpAddr,
+    category: Option<VarInt>,
+    token: Option<String>,
+}
+
+/// A connected labour as listed to admins.
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub id: u64,
+    pub ip: IpAddr,
+    pub category: Option<VarInt>,
+    pub token: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Roster {
+    next_id: AtomicU64,
+    labours: DashMap<u64, Entry>,
+    ips: DashMap<IpAddr, HashSet<u64>>,
+    subnets: DashMap<IpAddr, HashSet<u64>>,
+    tokens: DashMap<String, HashSet<u64>>,
+}
+
+fn index_insert<K: Eq + Hash>(index: &DashMap<K, HashSet<u64>>, key: K, id: u64) {
+    index.entry(key).or_default().insert(id);
+}
+
+fn index_remove<K: Eq + Hash>(index: &DashMap<K, HashSet<u64>>, key: &K, id: u64) {
+    if let Some(mut ids) = index.get_mut(key) {
+        ids.remove(&id);
+    }
+    index.remove_if(key, |_, ids| ids.is_empty());
+}
+
+fn index_get<K, Q>(index: &DashMap<K, HashSet<u64>>, key: &Q) -> Vec<u64>
+where
+    K: Eq + Hash + Borrow<Q>,
+    Q: Eq + Hash + ?Sized,
+{
+    index
+        .get(key)
+        .map(|ids| ids.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+impl Roster {
+    pub fn new() -> Roster {
+        Roster {
+            next_id: AtomicU64::new(1),
+            labours: DashMap::new(),
+            ips: DashMap::new(),
+            subnets: DashMap::new(),
+            tokens: DashMap::new(),
+        }
+    }
+
+    #[inline]
+    pub fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn employ(&self, id: u64, ip: IpAddr, addr: Addr<Labour>) {
+        index_insert(&self.ips, ip, id);
+        index_insert(&self.subnets, subnet(&ip), id);
+        self.labours.insert(
+            id,
+            Entry {
+                addr,
+                ip,
+                category: None,
+                token: None,
+            },
+        );
+    }
+
+    /// Index the labour by its token once it has shown a valid identity.
+    pub fn bind_token(&self, id: u64, category: VarInt, token: &str) {
+        if let Some(mut entry) = self.labours.get_mut(&id) {
+            entry.category = Some(category);
+            entry.token = Some(token.to_owned());
+        } else {
+            return;
+        }
+        index_insert(&self.tokens, token.to_owned(), id);
+    }
+
+    pub fn dismiss(&self, id: u64) {
+        if let Some((_, entry)) = self.labours.remove(&id) {
+            index_remove(&self.ips, &entry.ip, id);
+            index_remove(&self.subnets, &subnet(&entry.ip), id);
+            if let Some(token) = &entry.token {
+                index_remove(&self.tokens, token, id);
+            }
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<Addr<Labour>> {
+        self.labours.get(&id).map(|v| v.addr.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.labours.len()
+    }
+
+    pub fn members(&self) -> Vec<Member> {
+        let mut members: Vec<Member> = self
+            .labours
+            .iter()
+            .map(|v| Member {
+                id: *v.key(),
+                ip: v.ip,
+                category: v.category,
+                token: v.token.clone(),
+            })
+            .collect();
+        members.sort_by_key(|m| m.id);
+        members
+    }
+
+    pub fn ids_by_ip(&self, ip: &IpAddr) -> Vec<u64> {
+        index_get(&self.ips, ip)
+    }
+
+    pub fn ids_by_token(&self, token: &str) -> Vec<u64> {
+        index_get(&self.tokens, token)
+    }
+
+    pub fn count_ip(&self, ip: &IpAddr) -> usize {
+        self.ips.get(ip).map(|ids| ids.len()).unwrap_or(0)
+    }
+
+    pub fn count_subnet(&self, ip: &IpAddr) -> usize {
+        self.subnets
+            .get(&subnet(ip))
+            .map(|ids| ids.len())
+            .unwrap_or(0)
+    }
+
+    pub fn count_token(&self, token: &str) -> usize {
+        self.tokens.get(token).map(|ids| ids.len()).unwrap_or(0)
+    }
+
+    /// The labour unless its IP or token is on the allowlist.
+    fn get_unlisted(&self, id: u64) -> Option<Addr<Labour>> {
+        self.labours
+            .get(&id)
+            .filter(|v| !GUARD.is_allowed(&v.ip, v.token.as_deref().unwrap_or("")))
+            .map(|v| v.addr.clone())
+    }
+
+    /// Close every connection from the IP, except allowlisted ones.
+    pub fn sack_ip(&self, ip: &IpAddr, reason: Option<CloseReason>) {
+        for id in self.ids_by_ip(ip) {
+            if let Some(addr) = self.get_unlisted(id) {
+                addr.do_send(Sack(reason.clone()));
+            }
+        }
+    }
+
+    /// Close every connection using the token.
+    pub fn sack_token(&self, token: &str, reason: Option<CloseReason>) {
+        for id in self.ids_by_token(token) {
+            if let Some(addr) = self.get(id) {
+                addr.do_send(Sack(reason.clone()));
+            }
+        }
+    }
+
+    /// Close every connection from the address block, except allowlisted ones.
+    pub fn sack_cidr(&self, cidr: &Cidr, reason: Option<CloseReason>) {
+        let ids: Vec<u64> = self
+            .labours
+            .iter()
+            .filter(|v| cidr.contains(&v.ip))
+            .map(|v| *v.key())
+            .collect();
+        for id in ids {
+            if let Some(addr) = self.get_unlisted(id) {
+                addr.do_send(Sack(reason.clone()));
+            }
+        }
+    }
+
+    /// Push changed room assignments to the labours.
+    pub fn assign(&self, changes: Vec<(u64, Vec<String>)>) {
+        for (id, room_ids) in changes {
+            if let Some(addr) = self.get(id) {
+                addr.do_send(AssignTask(room_ids));
+            }
+        }
+    }
+
+    pub fn broadcast<M>(&self, msg: M)
+    where
+        M: Message + Send + Clone + 'static,
+        M::Result: Send,
+        Labour: Handler<M>,
+    {
+        let addrs: Vec<Addr<Labour>> = self.labours.iter().map(|v| v.addr.clone()).collect();
+        for addr in addrs {
+            addr.do_send(msg.clone());
+        }
+    }
+}
@@ -1,4 +1,5 @@
-use std::net::SocketAddr;
+use actix_web::HttpRequest;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash)]
@@ -10,6 +11,8 @@ pub enum State {
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash)]
 pub struct ConnectionInfo {
     pub peer_addr: SocketAddr,
+    /// Address of the client, taken from the proxy headers if the peer is a trusted proxy.
+    pub client_ip: IpAddr,
     pub scheme: String,
     pub host: String,
     pub real_remote_addr: Option<SocketAddr>,
@@ -17,9 +20,21 @@ pub struct ConnectionInfo {
 }
 
 impl ConnectionInfo {
-    pub fn new(info: &actix_web::dev::ConnectionInfo, peer_addr: SocketAddr) -> ConnectionInfo {
+    pub fn new(
+        req: &HttpRequest,
+        peer_addr: SocketAddr,
+        trusted_proxies: &[IpAddr],
+    ) -> ConnectionInfo {
+        let info = req.connection_info();
+        let client_ip = if trusted_proxies.contains(&peer_addr.ip()) {
+            forwarded_ip(req, info.realip_remote_addr(), trusted_proxies)
+                .unwrap_or_else(|| peer_addr.ip())
+        } else {
+            peer_addr.ip()
+        };
         ConnectionInfo {
             peer_addr,
+            client_ip,
             scheme: String::from(info.scheme()),
             host: String::from(info.host()),
             real_remote_addr: info
@@ -31,3 +46,48 @@ impl ConnectionInfo {
         }
     }
 }
+
+/// Take the client address from X-Real-IP, else the last address in
+/// X-Forwarded-For which is not a trusted proxy, else the Forwarded header.
+fn forwarded_ip(
+    req: &HttpRequest,
+    realip_remote_addr: Option<&str>,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+    if let Some(ip) = header("x-real-ip").and_then(parse_ip) {
+        return Some(ip);
+    }
+    if let Some(s) = header("x-forwarded-for") {
+        let ips: Vec<IpAddr> = s.split(',').filter_map(parse_ip).collect();
+        if let Some(ip) = ips.iter().rev().find(|ip| !trusted_proxies.contains(ip)) {
+            return Some(*ip);
+        }
+        return ips.first().cloned();
+    }
+    realip_remote_addr.and_then(parse_ip)
+}
+
+/// Parse `1.2.3.4`, `1.2.3.4:80`, `::1`, `[::1]:80` or the quoted forms of them.
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim().trim_matches('"');
+    IpAddr::from_str(s)
+        .ok()
+        .or_else(|| SocketAddr::from_str(s).ok().map(|a| a.ip()))
+        .or_else(|| IpAddr::from_str(s.trim_start_matches('[').trim_end_matches(']')).ok())
+}
+
+#[test]
+fn test() {
+    assert_eq!(parse_ip(" 1.2.3.4"), IpAddr::from_str("1.2.3.4").ok());
+    assert_eq!(parse_ip("1.2.3.4:80"), IpAddr::from_str("1.2.3.4").ok());
+    assert_eq!(
+        parse_ip("\"[2001:db8::1]:4711\""),
+        IpAddr::from_str("2001:db8::1").ok()
+    );
+    assert_eq!(
+        parse_ip("[2001:db8::1]"),
+        IpAddr::from_str("2001:db8::1").ok()
+    );
+    assert_eq!(parse_ip("unknown"), None);
+}
@@ -1,23 +1,38 @@
 #![feature(once_cell)]
 #![allow(unused)]
 
+use crate::federation::{Peer, Peers};
 use crate::guard::Guard;
+use crate::labour::message::Revalidate;
+use crate::labour::roster::Roster;
 use crate::labour::structs::ConnectionInfo;
+use crate::rate_limit::RateLimits;
+use crate::report::Reports;
+use crate::scheduler::Scheduler;
 use crate::settings::Settings;
+use crate::token::Tokens;
+use actix::{Actor, Arbiter};
 use actix_web::{get, post, web, App, Error, HttpRequest, HttpResponse, HttpServer, Responder};
 use actix_web_actors::ws;
-use log::{info, warn};
+use log::{debug, info, warn};
 use std::io::stdin;
 use std::lazy::SyncLazy;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
+mod console;
+mod federation;
 mod guard;
 mod labour;
 mod logger;
 mod packet;
+mod rate_limit;
+mod report;
+mod scheduler;
 mod settings;
 mod state;
+mod token;
 mod util;
 
 static SETTINGS: SyncLazy<Settings> = SyncLazy::new(|| {
@@ -30,14 +45,74 @@ static SETTINGS: SyncLazy<Settings> = SyncLazy::new(|| {
 
 static GUARD: SyncLazy<Guard> = SyncLazy::new(|| Guard::new(&SETTINGS));
 
+static TOKENS: SyncLazy<Tokens> = SyncLazy::new(|| Tokens::new(&SETTINGS));
+
+static ROSTER: SyncLazy<Roster> = SyncLazy::new(Roster::new);
+
+static SCHEDULER: SyncLazy<Scheduler> = SyncLazy::new(|| Scheduler::new(&SETTINGS));
+
+static REPORTS: SyncLazy<Reports> = SyncLazy::new(|| Reports::new(&SETTINGS));
+
+static RATE_LIMITS: SyncLazy<RateLimits> = SyncLazy::new(|| RateLimits::new(&SETTINGS));
+
+static PEERS: SyncLazy<Peers> = SyncLazy::new(Peers::new);
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     info!("Bilibili Live Synergetic Monitor starts to run...");
     let settings = &SETTINGS;
+    SyncLazy::force(&GUARD);
+    SyncLazy::force(&TOKENS);
+    SyncLazy::force(&SCHEDULER);
+    util::timer::run_interval("session-sweeper", Duration::from_secs(1), || {
+        ROSTER.assign(SCHEDULER.expire())
+    });
+    util::timer::run_interval("report-evictor", Duration::from_secs(10), || {
+        let n = REPORTS.evict();
+        if n > 0 {
+            debug!("Evicted {} expired reports, {} remain.", n, REPORTS.len());
+        }
+    });
+    util::timer::run_interval("loop-counter", Duration::from_secs(60), || {
+        let n = PEERS.take_loops();
+        if n > 0 {
+            info!(
+                "Dropped {} looping reports relayed by servers in the last minute.",
+                n
+            );
+        }
+    });
+    util::timer::run_interval("guard-pruner", Duration::from_secs(600), || GUARD.prune());
+    if settings.guard.save_interval > 0 {
+        util::timer::run_interval(
+            "guard-saver",
+            Duration::from_secs(settings.guard.save_interval),
+            || GUARD.save(),
+        );
+    }
+    if settings.token_files.reload_interval > 0 {
+        util::timer::run_interval(
+            "token-reloader",
+            Duration::from_secs(settings.token_files.reload_interval),
+            || {
+                if TOKENS.reload_if_modified() {
+                    ROSTER.broadcast(Revalidate);
+                }
+            },
+        );
+    }
     let addr = SocketAddr::new(settings.ip, settings.port);
     let server = HttpServer::new(move || App::new().service(ws_index))
         .bind(&addr)?
         .run();
+    if !settings.federation.peers.is_empty() {
+        // The console blocks this thread, so peers run on an arbiter of their own.
+        let arbiter = Arbiter::new();
+        for url in &settings.federation.peers {
+            let url = url.clone();
+            Peer::start_in_arbiter(&arbiter, move |_| Peer::new(url, &SETTINGS));
+        }
+    }
 
     loop {
         let mut s = String::new();
@@ -48,13 +123,11 @@ async fn main() -> std::io::Result<()> {
                 "stop" => {
                     info!("Bilibili Live Synergetic Monitor is stopping.");
                     server.stop(false).await;
+                    GUARD.save();
                     info!("Bilibili Live Synergetic Monitor has stopped.");
                     return Ok(());
                 }
-                "help" => {
-                    println!("stop: Close all connections and stop server.");
-                }
-                _ => println!("Unknown command \"{}\", type \"help\" for help.", s),
+                _ => console::execute(s),
             }
         }
     }
@@ -78,12 +151,20 @@ fn get_matches<'a>() -> clap::ArgMatches<'a> {
 #[get("/")]
 async fn ws_index(req: HttpRequest, payload: web::Payload) -> impl Responder {
     if let Some(addr) = req.peer_addr() {
-        if !&GUARD.check_addr(&addr) {
+        let connection_info = ConnectionInfo::new(&req, addr, &SETTINGS.trusted_proxies);
+        if !&GUARD.check_addr(&connection_info.client_ip) {
             return None;
         }
-        info!("Connection incoming: '{}'.", addr);
+        if connection_info.client_ip == addr.ip() {
+            info!("Connection incoming: '{}'.", addr);
+        } else {
+            info!(
+                "Connection incoming: '{}' via proxy '{}'.",
+                connection_info.client_ip, addr
+            );
+        }
         Some(ws::start(
-            labour::Labour::new(ConnectionInfo::new(&req.connection_info(), addr), &SETTINGS),
+            labour::Labour::new(connection_info),
             &req,
             payload,
         ))
@@ -2,6 +2,7 @@ use crate::packet::constants::*;
 use crate::packet::structs::*;
 use actix_web::web::{BufMut, Bytes, BytesMut};
 use std::any::{Any, TypeId};
+use std::cmp::min;
 
 pub mod constants;
 pub mod structs;
@@ -167,7 +168,8 @@ impl PacketData for TaskChange {
     #[inline]
     fn read_from_bytes(bytes: &mut Bytes) -> Option<Self> {
         let room_count = bytes.get_varint()?;
-        let mut room_ids = Vec::with_capacity(room_count as usize);
+        // Every entry takes a byte at least, so a forged count can't reserve more.
+        let mut room_ids = Vec::with_capacity(min(room_count as usize, bytes.len()));
         for _ in 0..room_count {
             room_ids.push(bytes.get_string()?)
         }
@@ -205,7 +207,7 @@ impl PacketData for TaskConfirm {
     #[inline]
     fn read_from_bytes(bytes: &mut Bytes) -> Option<Self> {
         let room_count = bytes.get_varint()?;
-        let mut room_ids = Vec::with_capacity(room_count as usize);
+        let mut room_ids = Vec::with_capacity(min(room_count as usize, bytes.len()));
         for _ in 0..room_count {
             room_ids.push(bytes.get_string()?)
         }
@@ -278,6 +280,443 @@ impl ToPacket for DataReport {
 
 /* ====================================== */
 
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RelayReport {
+    pub origin: String,
+    pub hops: VarInt,
+    pub report: DataReport,
+}
+
+impl PacketData for RelayReport {
+    #[inline]
+    fn read_from_bytes(bytes: &mut Bytes) -> Option<Self> {
+        let origin = bytes.get_string()?;
+        let hops = bytes.get_varint()?;
+        let report = DataReport::read_from_bytes(bytes)?;
+        Some(Self {
+            origin,
+            hops,
+            report,
+        })
+    }
+
+    #[inline]
+    fn write_to_bytes(&self, bytes: &mut BytesMut) {
+        bytes.put_string(&self.origin);
+        bytes.put_varint(self.hops);
+        self.report.write_to_bytes(bytes);
+    }
+}
+
+impl ToPacket for RelayReport {
+    #[inline]
+    fn to_packet(&self) -> Packet {
+        to_packet(self, id::RELAY_REPORT)
+    }
+}
+
+/* ====================================== */
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ServerId {
+    pub server_id: String,
+}
+
+impl PacketData for ServerId {
+    #[inline]
+    fn read_from_bytes(bytes: &mut Bytes) -> Option<Self> {
+        let server_id = bytes.get_string()?;
+        Some(Self { server_id })
+    }
+
+    #[inline]
+    fn write_to_bytes(&self, bytes: &mut BytesMut) {
+        bytes.put_string(&self.server_id);
+    }
+}
+
+impl ToPacket for ServerId {
+    #[inline]
+    fn to_packet(&self) -> Packet {
+        to_packet(self, id::SERVER_ID)
+    }
+}
+
+/* ====================================== */
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ListLabours;
+
+impl PacketData for ListLabours {
+    #[inline]
+    fn read_from_bytes(bytes: &mut Bytes) -> Option<Self> {
+        Some(Self)
+    }
+
+    #[inline]
+    fn write_to_bytes(&self, bytes: &mut BytesMut) {}
+}
+
+impl ToPacket for ListLabours {
+    #[inline]
+    fn to_packet(&self) -> Packet {
+        to_packet(self, id::LIST_LABOURS)
+    }
+}
+
+/* ====================================== */
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LabourInfo {
+    pub category: VarInt,
+    pub token: String,
+    pub ip: String,
+    pub room_count: VarInt,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LabourList {
+    pub labour_count: VarInt,
+    pub labours: Vec<LabourInfo>,
+}
+
+impl PacketData for LabourList {
+    #[inline]
+    fn read_from_bytes(bytes: &mut Bytes) -> Option<Self> {
+        let labour_count = bytes.get_varint()?;
+        let mut labours = Vec::with_capacity(min(labour_count as usize, bytes.len()));
+        for _ in 0..labour_count {
+            labours.push(LabourInfo {
+                category: bytes.get_varint()?,
+                token: bytes.get_string()?,
+                ip: bytes.get_string()?,
+                room_count: bytes.get_varint()?,
+            });
+        }
+        Some(Self {
+            labour_count,
+            labours,
+        })
+    }
+
+    #[inline]
+    fn write_to_bytes(&self, bytes: &mut BytesMut) {
+        bytes.put_varint(self.labour_count);
+        for labour in &self.labours {
+            bytes.put_varint(labour.category);
+            bytes.put_string(&labour.token);
+            bytes.put_string(&labour.ip);
+            bytes.put_varint(labour.room_count);
+        }
+    }
+}
+
+impl ToPacket for LabourList {
+    #[inline]
+    fn to_packet(&self) -> Packet {
+        to_packet(self, id::LABOUR_LIST)
+    }
+}
+
+/* ====================================== */
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AdminKick {
+    pub target: String,
+}
+
+impl PacketData for AdminKick {
+    #[inline]
+    fn read_from_bytes(bytes: &mut Bytes) -> Option<Self> {
+        let target = bytes.get_string()?;
+        Some(Self { target })
+    }
+
+    #[inline]
+    fn write_to_bytes(&self, bytes: &mut BytesMut) {
+        bytes.put_string(&self.target);
+    }
+}
+
+impl ToPacket for AdminKick {
+    #[inline]
+    fn to_packet(&self) -> Packet {
+        to_packet(self, id::ADMIN_KICK)
+    }
+}
+
+/* ====================================== */
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AdminBan {
+    pub target: String,
+    pub hours: VarInt,
+    pub reason: String,
+}
+
+impl PacketData for AdminBan {
+    #[inline]
+    fn read_from_bytes(bytes: &mut Bytes) -> Option<Self> {
+        let target = bytes.get_string()?;
+        let hours = bytes.get_varint()?;
+        let reason = bytes.get_string()?;
+        Some(Self {
+            target,
+            hours,
+            reason,
+        })
+    }
+
+    #[inline]
+    fn write_to_bytes(&self, bytes: &mut BytesMut) {
+        bytes.put_string(&self.target);
+        bytes.put_varint(self.hours);
+        bytes.put_string(&self.reason);
+    }
+}
+
+impl ToPacket for AdminBan {
+    #[inline]
+    fn to_packet(&self) -> Packet {
+        to_packet(self, id::ADMIN_BAN)
+    }
+}
+
+/* ====================================== */
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AdminRateLimit {
+    pub scope: VarInt,
+    pub key: String,
+    pub interval: VarInt,
+    pub max_burst: VarInt,
+}
+
+impl PacketData for AdminRateLimit {
+    #[inline]
+    fn read_from_bytes(bytes: &mut Bytes) -> Option<Self> {
+        let scope = bytes.get_varint()?;
+        let key = bytes.get_string()?;
+        let interval = bytes.get_varint()?;
+        let max_burst = bytes.get_varint()?;
+        Some(Self {
+            scope,
+            key,
+            interval,
+            max_burst,
+        })
+    }
+
+    #[inline]
+    fn write_to_bytes(&self, bytes: &mut BytesMut) {
+        bytes.put_varint(self.scope);
+        bytes.put_string(&self.key);
+        bytes.put_varint(self.interval);
+        bytes.put_varint(self.max_burst);
+    }
+}
+
+impl ToPacket for AdminRateLimit {
+    #[inline]
+    fn to_packet(&self) -> Packet {
+        to_packet(self, id::ADMIN_RATE_LIMIT)
+    }
+}
+
+/* ====================================== */
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AdminRooms {
+    pub action: VarInt,
+    pub room_count: VarInt,
+    pub room_ids: Vec<String>,
+}
+
+impl PacketData for AdminRooms {
+    #[inline]
+    fn read_from_bytes(bytes: &mut Bytes) -> Option<Self> {
+        let action = bytes.get_varint()?;
+        let room_count = bytes.get_varint()?;
+        let mut room_ids = Vec::with_capacity(min(room_count as usize, bytes.len()));
+        for _ in 0..room_count {
+            room_ids.push(bytes.get_string()?)
+        }
+        Some(Self {
+            action,
+            room_count,
+            room_ids,
+        })
+    }
+
+    #[inline]
+    fn write_to_bytes(&self, bytes: &mut BytesMut) {
+        bytes.put_varint(self.action);
+        bytes.put_varint(self.room_count);
+        for room_id in &self.room_ids {
+            bytes.put_string(room_id);
+        }
+    }
+}
+
+impl ToPacket for AdminRooms {
+    #[inline]
+    fn to_packet(&self) -> Packet {
+        to_packet(self, id::ADMIN_ROOMS)
+    }
+}
+
+/* ====================================== */
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct GetStats;
+
+impl PacketData for GetStats {
+    #[inline]
+    fn read_from_bytes(bytes: &mut Bytes) -> Option<Self> {
+        Some(Self)
+    }
+
+    #[inline]
+    fn write_to_bytes(&self, bytes: &mut BytesMut) {}
+}
+
+impl ToPacket for GetStats {
+    #[inline]
+    fn to_packet(&self) -> Packet {
+        to_packet(self, id::GET_STATS)
+    }
+}
+
+/* ====================================== */
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Stats {
+    pub labour_count: VarInt,
+    pub server_count: VarInt,
+    pub room_count: VarInt,
+    pub owned_room_count: VarInt,
+    pub assigned_room_count: VarInt,
+    pub report_count: VarInt,
+    pub ban_count: VarInt,
+}
+
+impl PacketData for Stats {
+    #[inline]
+    fn read_from_bytes(bytes: &mut Bytes) -> Option<Self> {
+        let labour_count = bytes.get_varint()?;
+        let server_count = bytes.get_varint()?;
+        let room_count = bytes.get_varint()?;
+        let owned_room_count = bytes.get_varint()?;
+        let assigned_room_count = bytes.get_varint()?;
+        let report_count = bytes.get_varint()?;
+        let ban_count = bytes.get_varint()?;
+        Some(Self {
+            labour_count,
+            server_count,
+            room_count,
+            owned_room_count,
+            assigned_room_count,
+            report_count,
+            ban_count,
+        })
+    }
+
+    #[inline]
+    fn write_to_bytes(&self, bytes: &mut BytesMut) {
+        bytes.put_varint(self.labour_count);
+        bytes.put_varint(self.server_count);
+        bytes.put_varint(self.room_count);
+        bytes.put_varint(self.owned_room_count);
+        bytes.put_varint(self.assigned_room_count);
+        bytes.put_varint(self.report_count);
+        bytes.put_varint(self.ban_count);
+    }
+}
+
+impl ToPacket for Stats {
+    #[inline]
+    fn to_packet(&self) -> Packet {
+        to_packet(self, id::STATS)
+    }
+}
+
+/* ====================================== */
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AdminResult {
+    pub request_id: VarInt,
+    pub code: VarInt,
+    pub message: String,
+}
+
+impl PacketData for AdminResult {
+    #[inline]
+    fn read_from_bytes(bytes: &mut Bytes) -> Option<Self> {
+        let request_id = bytes.get_varint()?;
+        let code = bytes.get_varint()?;
+        let message = bytes.get_string()?;
+        Some(Self {
+            request_id,
+            code,
+            message,
+        })
+    }
+
+    #[inline]
+    fn write_to_bytes(&self, bytes: &mut BytesMut) {
+        bytes.put_varint(self.request_id);
+        bytes.put_varint(self.code);
+        bytes.put_string(&self.message);
+    }
+}
+
+impl ToPacket for AdminResult {
+    #[inline]
+    fn to_packet(&self) -> Packet {
+        to_packet(self, id::ADMIN_RESULT)
+    }
+}
+
+/* ====================================== */
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Subscription {
+    pub category_count: VarInt,
+    pub categories: Vec<VarInt>,
+}
+
+impl PacketData for Subscription {
+    #[inline]
+    fn read_from_bytes(bytes: &mut Bytes) -> Option<Self> {
+        let category_count = bytes.get_varint()?;
+        let mut categories = Vec::with_capacity(min(category_count as usize, bytes.len()));
+        for _ in 0..category_count {
+            categories.push(bytes.get_varint()?)
+        }
+        Some(Self {
+            category_count,
+            categories,
+        })
+    }
+
+    #[inline]
+    fn write_to_bytes(&self, bytes: &mut BytesMut) {
+        bytes.put_varint(self.category_count);
+        for c in &self.categories {
+            bytes.put_varint(*c);
+        }
+    }
+}
+
+impl ToPacket for Subscription {
+    #[inline]
+    fn to_packet(&self) -> Packet {
+        to_packet(self, id::SUBSCRIPTION)
+    }
+}
+
+/* ====================================== */
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Notification {
     pub category: VarInt,
@@ -349,4 +788,9 @@ fn test() {
     let mut p2 = Packet::read_from_bytes(&mut bytes.clone().freeze()).unwrap();
     println!("{:?}", ShowIdentity::read_from_bytes(&mut p2.data));
     println!("{:?}", bytes.clone());
+    println!("=======================");
+    let mut bytes = BytesMut::new();
+    bytes.put_varint(u32::MAX);
+    bytes.put_string("1");
+    assert_eq!(TaskConfirm::read_from_bytes(&mut bytes.freeze()), None);
 }
@@ -9,6 +9,18 @@ pub mod id {
     pub const TASK_CHANGE: VarInt = 0x04;
     pub const TASK_CONFIRM: VarInt = 0x05;
     pub const DATA_REPORT: VarInt = 0x06;
+    pub const SUBSCRIPTION: VarInt = 0x07;
+    pub const RELAY_REPORT: VarInt = 0x08;
+    pub const SERVER_ID: VarInt = 0x09;
+    pub const LIST_LABOURS: VarInt = 0x0A;
+    pub const LABOUR_LIST: VarInt = 0x0B;
+    pub const ADMIN_KICK: VarInt = 0x0C;
+    pub const ADMIN_BAN: VarInt = 0x0D;
+    pub const ADMIN_RATE_LIMIT: VarInt = 0x0E;
+    pub const ADMIN_ROOMS: VarInt = 0x0F;
+    pub const GET_STATS: VarInt = 0x10;
+    pub const STATS: VarInt = 0x11;
+    pub const ADMIN_RESULT: VarInt = 0x12;
     pub const NOTIFICATION: VarInt = 0xFF;
 }
 
@@ -31,3 +43,31 @@ pub mod data_report {
         pub const LOTTERY: VarInt = 3;
     }
 }
+
+pub mod admin_rate_limit {
+    pub mod scope {
+        use crate::packet::structs::VarInt;
+
+        pub const GLOBAL: VarInt = 0;
+        pub const CATEGORY: VarInt = 1;
+        pub const TOKEN: VarInt = 2;
+    }
+}
+
+pub mod admin_rooms {
+    pub mod action {
+        use crate::packet::structs::VarInt;
+
+        pub const ADD: VarInt = 1;
+        pub const REMOVE: VarInt = 2;
+    }
+}
+
+pub mod admin_result {
+    pub mod code {
+        use crate::packet::structs::VarInt;
+
+        pub const OK: VarInt = 0;
+        pub const FAILED: VarInt = 1;
+    }
+}
@@ -0,0 +1,144 @@
+use crate::packet::structs::VarInt;
+use crate::settings::{RateLimit, Settings};
+use dashmap::DashMap;
+use std::sync::RwLock;
+
+/// Rate limits which can be changed at runtime. The most specific one applies to
+/// a labour: the one of its token, then the one of its category, then the global one.
+#[derive(Debug)]
+pub struct RateLimits {
+    global: RwLock<RateLimit>,
+    categories: DashMap<VarInt, RateLimit>,
+    tokens: DashMap<String, RateLimit>,
+}
+
+impl RateLimits {
+    pub fn new(settings: &Settings) -> RateLimits {
+        RateLimits {
+            global: RwLock::new(settings.rate_limit.clone()),
+            categories: settings
+                .category_rate_limits
+                .iter()
+                .map(|(c, rate_limit)| (*c, rate_limit.clone()))
+                .collect(),
+            tokens: DashMap::new(),
+        }
+    }
+
+    pub fn get(&self, category: Option<VarInt>, token: &str) -> RateLimit {
+        if let Some(rate_limit) = self.tokens.get(token) {
+            return rate_limit.value().clone();
+        }
+        if let Some(rate_limit) = category.and_then(|c| self.categories.get(&c)) {
+            return rate_limit.value().clone();
+        }
+        self.global.read().unwrap().clone()
+    }
+
+    pub fn global(&self) -> RateLimit {
+        self.global.read().unwrap().clone()
+    }
+
+    pub fn set_global(&self, rate_limit: RateLimit) {
+        *self.global.write().unwrap() = rate_limit;
+    }
+
+    /// Set the rate limit of a category, `None` falls back to the global one.
+    pub fn set_category(&self, category: VarInt, rate_limit: Option<RateLimit>) {
+        match rate_limit {
+            Some(rate_limit) => self.categories.insert(category, rate_limit),
+            None => self.categories.remove(&category).map(|(_, v)| v),
+        };
+    }
+
+    /// Set the rate limit of a token, `None` falls back to the one of its category.
+    pub fn set_token(&self, token: &str, rate_limit: Option<RateLimit>) {
+        match rate_limit {
+            Some(rate_limit) => self.tokens.insert(token.to_owned(), rate_limit),
+            None => self.tokens.remove(token).map(|(_, v)| v),
+        };
+    }
+
+    pub fn categories(&self) -> Vec<(VarInt, RateLimit)> {
+        let mut v: Vec<(VarInt, RateLimit)> = self
+            .categories
+            .iter()
+            .map(|e| (*e.key(), e.value().clone()))
+            .collect();
+        v.sort_by_key(|(c, _)| *c);
+        v
+    }
+
+    pub fn tokens(&self) -> Vec<(String, RateLimit)> {
+        let mut v: Vec<(String, RateLimit)> = self
+            .tokens
+            .iter()
+            .map(|e| (e.key().clone(), e.value().clone()))
+            .collect();
+        v.sort_by(|a, b| a.0.cmp(&b.0));
+        v
+    }
+}
+
+/// The longest heartbeat timeout, `interval * max_burst`, a rate limit may give.
+pub const MAX_HEARTBEAT_TIMEOUT: u64 = 24 * 3600 * 1000;
+
+/// A rate limit must allow a packet and must not stretch the heartbeat timeout
+/// past a day.
+pub fn is_valid(rate_limit: &RateLimit) -> bool {
+    rate_limit.interval > 0
+        && rate_limit.max_burst > 0
+        && rate_limit.interval as u64 * rate_limit.max_burst as u64 <= MAX_HEARTBEAT_TIMEOUT
+}
+
+/// A rate limit is looser if it allows at least as much traffic in any period,
+/// so the rate limiter can take effect before the client adapts to it.
+pub fn is_looser(new: &RateLimit, old: &RateLimit) -> bool {
+    new.interval <= old.interval && new.max_burst >= old.max_burst
+}
+
+/// Returns `true` if neither the response timeout, `interval * 2`, nor the
+/// heartbeat timeout, `interval * max_burst`, gets shorter with the new rate limit.
+pub fn keeps_timeouts(new: &RateLimit, old: &RateLimit) -> bool {
+    new.interval >= old.interval
+        && new.interval as u64 * new.max_burst as u64 >= old.interval as u64 * old.max_burst as u64
+}
+
+#[test]
+fn test() {
+    let rate_limits = RateLimits::new(&Settings::default());
+    let global = Settings::default().rate_limit;
+    let strict = RateLimit {
+        interval: 20000,
+        max_burst: 3,
+    };
+    let loose = RateLimit {
+        interval: 1000,
+        max_burst: 60,
+    };
+    rate_limits.set_category(1, Some(loose.clone()));
+    rate_limits.set_token("a", Some(strict.clone()));
+    assert_eq!(rate_limits.get(Some(1), "a"), strict);
+    assert_eq!(rate_limits.get(Some(1), "b"), loose);
+    assert_eq!(rate_limits.get(Some(3), "b"), global);
+    rate_limits.set_token("a", None);
+    assert_eq!(rate_limits.get(Some(1), "a"), loose);
+    let bursty = RateLimit {
+        interval: global.interval,
+        max_burst: global.max_burst * 2,
+    };
+    // A shorter interval allows more traffic but shortens both timeouts.
+    assert!(is_looser(&loose, &global));
+    assert!(!keeps_timeouts(&loose, &global));
+    assert!(!is_looser(&strict, &global));
+    assert!(keeps_timeouts(&strict, &global));
+    assert!(is_looser(&bursty, &global));
+    assert!(keeps_timeouts(&bursty, &global));
+    assert!(is_looser(&global, &global));
+    assert!(keeps_timeouts(&global, &global));
+    assert!(is_valid(&global));
+    assert!(!is_valid(&RateLimit {
+        interval: u32::MAX,
+        max_burst: 2,
+    }));
+}
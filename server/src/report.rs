@@ -0,0 +1,117 @@
+use crate::packet::constants::data_report::category;
+use crate::packet::structs::VarInt;
+use crate::packet::DataReport;
+use crate::settings::Settings;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use fixedbitset::FixedBitSet;
+use std::cmp::min;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub enum Check {
+    New,
+    Duplicate,
+    Expired,
+}
+
+#[derive(Debug)]
+pub struct Reports {
+    seen: DashMap<(VarInt, String, String), Instant>,
+    max_time: VarInt,
+    retention: Duration,
+}
+
+impl Reports {
+    pub fn new(settings: &Settings) -> Reports {
+        Reports {
+            seen: DashMap::new(),
+            max_time: settings.report.max_time,
+            retention: Duration::from_secs(settings.report.retention),
+        }
+    }
+
+    /// Remember the report until its `time` (in seconds) has passed. Reports with the same
+    /// `(category, room_id, id)` are duplicates before that and expired afterwards.
+    pub fn check(&self, report: &DataReport) -> Check {
+        let key = (report.category, report.room_id.clone(), report.id.clone());
+        let now = Instant::now();
+        match self.seen.entry(key) {
+            Entry::Occupied(e) => {
+                if *e.get() > now {
+                    Check::Duplicate
+                } else {
+                    Check::Expired
+                }
+            }
+            Entry::Vacant(e) => {
+                let time = min(report.time, self.max_time);
+                e.insert(now + Duration::from_secs(time as u64));
+                Check::New
+            }
+        }
+    }
+
+    /// Forget the reports which expired longer than the retention ago.
+    /// Returns the number of evicted reports.
+    pub fn evict(&self) -> usize {
+        let len = self.seen.len();
+        let now = Instant::now();
+        let retention = self.retention;
+        self.seen
+            .retain(|_, expires_at| *expires_at + retention > now);
+        len - self.seen.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+}
+
+pub const CATEGORIES: [VarInt; 3] = [category::STORM, category::SPECIAL_GIFT, category::LOTTERY];
+
+pub fn is_valid_category(c: VarInt) -> bool {
+    CATEGORIES.contains(&c)
+}
+
+/// Build the set of categories a labour subscribes to.
+/// Returns `None` if any category is invalid.
+pub fn subscription(categories: &[VarInt]) -> Option<FixedBitSet> {
+    let mut set = FixedBitSet::with_capacity(category::LOTTERY as usize + 1);
+    for c in categories {
+        if !is_valid_category(*c) {
+            return None;
+        }
+        set.insert(*c as usize);
+    }
+    Some(set)
+}
+
+#[test]
+fn test() {
+    let reports = Reports {
+        seen: DashMap::new(),
+        max_time: 3600,
+        retention: Duration::from_secs(0),
+    };
+    let mut report = DataReport {
+        category: category::LOTTERY,
+        room_id: String::from("1"),
+        id: String::from("2"),
+        time: 60,
+        detail: String::new(),
+    };
+    assert_eq!(reports.check(&report), Check::New);
+    assert_eq!(reports.check(&report), Check::Duplicate);
+    report.id = String::from("3");
+    report.time = 0;
+    assert_eq!(reports.check(&report), Check::New);
+    assert_eq!(reports.check(&report), Check::Expired);
+    assert_eq!(reports.evict(), 1);
+    assert_eq!(reports.len(), 1);
+    let set = subscription(&[category::LOTTERY]).unwrap();
+    assert!(set.contains(category::LOTTERY as usize));
+    assert!(!set.contains(category::STORM as usize));
+    assert!(!set.contains(100));
+    assert!(subscription(&[100]).is_none());
+}
@@ -0,0 +1,520 @@
+use crate::settings::Settings;
+use crate::util::read_list_file;
+use log::info;
+use std::cmp::min;
+use std::collections::{BTreeSet, HashMap};
+use std::iter::once;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default)]
+struct Assignment {
+    capacity: usize,
+    rooms: BTreeSet<String>,
+}
+
+/// Rooms of a disconnected labour, kept until it comes back or the grace period ends.
+#[derive(Debug)]
+struct Session {
+    assignment: Assignment,
+    expires_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// All rooms of the room file, `pool` is the share of this server.
+    rooms: BTreeSet<String>,
+    pool: BTreeSet<String>,
+    server_id: String,
+    servers: BTreeSet<String>,
+    owners: HashMap<String, u64>,
+    assignments: HashMap<u64, Assignment>,
+    sessions: HashMap<String, Session>,
+}
+
+impl Inner {
+    fn unassigned(&self) -> impl Iterator<Item = &String> {
+        let owners = &self.owners;
+        self.pool
+            .iter()
+            .filter(move |room| !owners.contains_key(*room))
+    }
+
+    fn take(&mut self, id: u64, room: String) {
+        self.owners.insert(room.clone(), id);
+        if let Some(assignment) = self.assignments.get_mut(&id) {
+            assignment.rooms.insert(room);
+        }
+    }
+
+    fn give_back(&mut self, id: u64, room: &str) {
+        self.owners.remove(room);
+        if let Some(assignment) = self.assignments.get_mut(&id) {
+            assignment.rooms.remove(room);
+        }
+    }
+
+    fn free(&mut self, rooms: &BTreeSet<String>) {
+        for room in rooms {
+            self.owners.remove(room);
+        }
+    }
+
+    /// Give the rooms kept in the session of `token` back to the labour.
+    fn restore(&mut self, id: u64, token: &str) -> bool {
+        if self.assignments.contains_key(&id) {
+            return false;
+        }
+        match self.sessions.remove(token) {
+            Some(session) => {
+                for room in &session.assignment.rooms {
+                    self.owners.insert(room.clone(), id);
+                }
+                self.assignments.insert(id, session.assignment);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn rooms_of(&self, id: u64) -> Vec<String> {
+        self.assignments
+            .get(&id)
+            .map(|a| a.rooms.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Hand unmonitored rooms to the least loaded labours with spare capacity.
+    fn distribute(&mut self, changed: &mut BTreeSet<u64>) {
+        let rooms: Vec<String> = self.unassigned().cloned().collect();
+        for room in rooms {
+            let id = self
+                .assignments
+                .iter()
+                .filter(|(_, a)| a.rooms.len() < a.capacity)
+                .min_by_key(|(id, a)| (a.rooms.len(), **id))
+                .map(|(id, _)| *id);
+            match id {
+                Some(id) => {
+                    self.take(id, room);
+                    changed.insert(id);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Move rooms from the most loaded labours to `id` until the load is even.
+    fn balance(&mut self, id: u64, changed: &mut BTreeSet<u64>) {
+        loop {
+            let (load, capacity) = match self.assignments.get(&id) {
+                Some(a) => (a.rooms.len(), a.capacity),
+                None => return,
+            };
+            if load >= capacity {
+                return;
+            }
+            let victim = self
+                .assignments
+                .iter()
+                .filter(|(v, _)| **v != id)
+                .max_by_key(|(v, a)| (a.rooms.len(), **v))
+                .filter(|(_, a)| a.rooms.len() > load + 1)
+                .and_then(|(v, a)| a.rooms.iter().next_back().map(|room| (*v, room.clone())));
+            match victim {
+                Some((v, room)) => {
+                    self.give_back(v, &room);
+                    self.take(id, room);
+                    changed.insert(v);
+                    changed.insert(id);
+                }
+                None => return,
+            }
+        }
+    }
+
+    fn changes(&self, changed: BTreeSet<u64>) -> Vec<(u64, Vec<String>)> {
+        changed
+            .into_iter()
+            .filter(|id| self.assignments.contains_key(id))
+            .map(|id| (id, self.rooms_of(id)))
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct Scheduler {
+    inner: Mutex<Inner>,
+    grace: Duration,
+}
+
+impl Scheduler {
+    pub fn new(settings: &Settings) -> Scheduler {
+        let rooms: BTreeSet<String> = read_list_file(&settings.task.room_file)
+            .into_iter()
+            .collect();
+        info!("Loaded {} rooms into the pool.", rooms.len());
+        let server_id = settings.federation.server_id.clone();
+        Scheduler {
+            inner: Mutex::new(Inner {
+                pool: rooms.clone(),
+                rooms,
+                servers: once(server_id.clone()).collect(),
+                server_id,
+                ..Inner::default()
+            }),
+            grace: Duration::from_millis(settings.task.session_grace),
+        }
+    }
+
+    /// Set the capacity of a labour, fill it up with unmonitored rooms and take
+    /// over rooms from busier labours. Returns the new rooms of every labour whose
+    /// assignment changed, starting with the applying labour itself.
+    pub fn apply(&self, id: u64, token: &str, capacity: usize) -> Vec<(u64, Vec<String>)> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.restore(id, token) {
+            info!("Session of Labour '{}' is restored.", token);
+        }
+        let assignment = inner.assignments.entry(id).or_default();
+        assignment.capacity = capacity;
+        let extra: Vec<String> = assignment.rooms.iter().skip(capacity).cloned().collect();
+        for room in &extra {
+            inner.give_back(id, room);
+        }
+        let mut changed = BTreeSet::new();
+        inner.distribute(&mut changed);
+        inner.balance(id, &mut changed);
+        changed.remove(&id);
+        let mut changes = vec![(id, inner.rooms_of(id))];
+        changes.extend(inner.changes(changed));
+        changes
+    }
+
+    /// Rooms currently assigned to a labour.
+    pub fn assignment(&self, id: u64) -> Vec<String> {
+        self.inner.lock().unwrap().rooms_of(id)
+    }
+
+    /// Accept the confirmed rooms if they match the assignment exactly, restoring
+    /// the session of a reconnected labour first. Otherwise returns the corrective
+    /// changes, treating an unknown labour as if it applied for that many rooms.
+    pub fn confirm(
+        &self,
+        id: u64,
+        token: &str,
+        room_ids: &[String],
+    ) -> Result<(), Vec<(u64, Vec<String>)>> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.restore(id, token) {
+            info!("Session of Labour '{}' is restored.", token);
+        }
+        if let Some(assignment) = inner.assignments.get_mut(&id) {
+            if assignment.rooms.len() == room_ids.len()
+                && room_ids.iter().all(|room| assignment.rooms.contains(room))
+            {
+                return Ok(());
+            }
+            return Err(vec![(id, inner.rooms_of(id))]);
+        }
+        drop(inner);
+        Err(self.apply(id, token, room_ids.len()))
+    }
+
+    /// Forget a labour. Its rooms are kept in a session for the grace period, but
+    /// no longer than `grace`, if it has a token, otherwise they are handed over to
+    /// the others at once. Returns the new rooms of every labour whose assignment changed.
+    pub fn release(&self, id: u64, token: &str, grace: Duration) -> Vec<(u64, Vec<String>)> {
+        let grace = min(self.grace, grace);
+        let mut inner = self.inner.lock().unwrap();
+        let mut changed = BTreeSet::new();
+        if let Some(assignment) = inner.assignments.remove(&id) {
+            if assignment.rooms.is_empty() {
+                return Vec::new();
+            }
+            if grace.as_millis() > 0 && !token.is_empty() {
+                info!(
+                    "{} rooms of Labour '{}' are kept for {}s.",
+                    assignment.rooms.len(),
+                    token,
+                    grace.as_secs_f32()
+                );
+                let session = Session {
+                    assignment,
+                    expires_at: Instant::now() + grace,
+                };
+                if let Some(old) = inner.sessions.insert(token.to_owned(), session) {
+                    inner.free(&old.assignment.rooms);
+                    inner.distribute(&mut changed);
+                }
+                return inner.changes(changed);
+            }
+            info!(
+                "{} rooms are released by Labour '{}'.",
+                assignment.rooms.len(),
+                token
+            );
+            inner.free(&assignment.rooms);
+            inner.distribute(&mut changed);
+        }
+        inner.changes(changed)
+    }
+
+    /// Hand the rooms of expired sessions over to the working labours.
+    pub fn expire(&self) -> Vec<(u64, Vec<String>)> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let expired: Vec<String> = inner
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.expires_at <= now)
+            .map(|(token, _)| token.clone())
+            .collect();
+        let mut changed = BTreeSet::new();
+        if expired.is_empty() {
+            return Vec::new();
+        }
+        for token in expired {
+            if let Some(session) = inner.sessions.remove(&token) {
+                info!(
+                    "Session of Labour '{}' expired, releasing {} rooms.",
+                    token,
+                    session.assignment.rooms.len()
+                );
+                inner.free(&session.assignment.rooms);
+            }
+        }
+        inner.distribute(&mut changed);
+        inner.changes(changed)
+    }
+
+    /// Add rooms to the pool and hand the ones of this server to the labours.
+    /// Returns the number of new rooms and the changed assignments.
+    pub fn add_rooms(&self, room_ids: &[String]) -> (usize, Vec<(u64, Vec<String>)>) {
+        let mut inner = self.inner.lock().unwrap();
+        let mut added = 0;
+        for room in room_ids {
+            if !inner.rooms.insert(room.clone()) {
+                continue;
+            }
+            added += 1;
+            if owner(room, &inner.servers) == Some(&inner.server_id) {
+                inner.pool.insert(room.clone());
+            }
+        }
+        let mut changed = BTreeSet::new();
+        inner.distribute(&mut changed);
+        (added, inner.changes(changed))
+    }
+
+    /// Remove rooms from the pool and from the labours monitoring them. Returns
+    /// the number of removed rooms and the changed assignments.
+    pub fn remove_rooms(&self, room_ids: &[String]) -> (usize, Vec<(u64, Vec<String>)>) {
+        let mut inner = self.inner.lock().unwrap();
+        let mut removed = 0;
+        let mut changed = BTreeSet::new();
+        for room in room_ids {
+            if !inner.rooms.remove(room) {
+                continue;
+            }
+            removed += 1;
+            inner.pool.remove(room);
+            if let Some(id) = inner.owners.get(room).copied() {
+                inner.give_back(id, room);
+                changed.insert(id);
+            }
+            for session in inner.sessions.values_mut() {
+                session.assignment.rooms.remove(room);
+            }
+        }
+        (removed, inner.changes(changed))
+    }
+
+    /// Numbers of all rooms, rooms of this server and rooms taken by a labour.
+    pub fn counts(&self) -> (usize, usize, usize) {
+        let inner = self.inner.lock().unwrap();
+        (inner.rooms.len(), inner.pool.len(), inner.owners.len())
+    }
+
+    /// Keep only the rooms which hash to this server among the working servers,
+    /// handing the others over and taking the ones of a server which has gone away.
+    /// Returns the new rooms of every labour whose assignment changed.
+    pub fn reshard(&self, mut servers: BTreeSet<String>) -> Vec<(u64, Vec<String>)> {
+        let mut inner = self.inner.lock().unwrap();
+        servers.insert(inner.server_id.clone());
+        if inner.servers == servers {
+            return Vec::new();
+        }
+        inner.servers = servers;
+        let pool: BTreeSet<String> = inner
+            .rooms
+            .iter()
+            .filter(|room| owner(room, &inner.servers) == Some(&inner.server_id))
+            .cloned()
+            .collect();
+        let lost: Vec<String> = inner.pool.difference(&pool).cloned().collect();
+        let mut changed = BTreeSet::new();
+        for room in &lost {
+            if let Some(id) = inner.owners.get(room).copied() {
+                inner.give_back(id, room);
+                changed.insert(id);
+            }
+            for session in inner.sessions.values_mut() {
+                session.assignment.rooms.remove(room);
+            }
+        }
+        info!(
+            "{} servers are working, this one owns {} of {} rooms and hands {} over.",
+            inner.servers.len(),
+            pool.len(),
+            inner.rooms.len(),
+            lost.len()
+        );
+        inner.pool = pool;
+        inner.distribute(&mut changed);
+        inner.changes(changed)
+    }
+}
+
+/// The server which owns a room by rendezvous hashing, so that only the rooms of
+/// a server which joins or leaves move.
+fn owner<'a>(room: &str, servers: &'a BTreeSet<String>) -> Option<&'a String> {
+    servers
+        .iter()
+        .max_by_key(|server| (hash(&[server, room]), *server))
+}
+
+/// FNV-1a with a final mix, which unlike the std hasher is the same on every server.
+fn hash(parts: &[&str]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in parts.iter().flat_map(|part| part.bytes().chain(once(0))) {
+        h ^= byte as u64;
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h
+}
+
+#[test]
+fn test() {
+    let scheduler = Scheduler {
+        inner: Mutex::new(Inner {
+            pool: (1..=6).map(|i| i.to_string()).collect(),
+            ..Inner::default()
+        }),
+        grace: Duration::from_secs(0),
+    };
+    let changes = scheduler.apply(1, "a", 4);
+    assert_eq!(changes.len(), 1);
+    let a = changes[0].1.clone();
+    assert_eq!(a.len(), 4);
+    assert!(scheduler.confirm(1, "a", &a[..2]).is_err());
+    assert!(scheduler.confirm(1, "a", &a).is_ok());
+    // The second labour takes the rest and one room from the first.
+    let changes = scheduler.apply(2, "b", 4);
+    assert_eq!(changes.len(), 2);
+    assert_eq!(changes[0].0, 2);
+    assert_eq!(changes[0].1.len(), 3);
+    assert_eq!(changes[1], (1, scheduler.assignment(1)));
+    assert_eq!(scheduler.assignment(1).len(), 3);
+    // The rooms of a released labour go to the survivors.
+    scheduler.apply(3, "c", 2);
+    let changes = scheduler.release(2, "b", Duration::from_secs(60));
+    assert_eq!(changes, vec![(1, scheduler.assignment(1))]);
+    assert_eq!(
+        scheduler.assignment(1).len() + scheduler.assignment(3).len(),
+        6
+    );
+    assert_eq!(scheduler.apply(1, "a", 1)[0].1.len(), 1);
+}
+
+#[test]
+fn test_session() {
+    let scheduler = Scheduler {
+        inner: Mutex::new(Inner {
+            pool: (1..=4).map(|i| i.to_string()).collect(),
+            ..Inner::default()
+        }),
+        grace: Duration::from_millis(50),
+    };
+    let a = scheduler.apply(1, "a", 2)[0].1.clone();
+    scheduler.apply(2, "b", 2);
+    // The rooms of a disconnected labour are kept for it.
+    assert!(scheduler
+        .release(1, "a", Duration::from_secs(60))
+        .is_empty());
+    let c = scheduler.apply(3, "c", 2)[0].1.clone();
+    assert!(c.iter().all(|room| !a.contains(room)));
+    // A reconnected labour gets its rooms back by confirming them.
+    assert!(scheduler.confirm(4, "a", &a).is_ok());
+    assert_eq!(scheduler.assignment(4), a);
+    // A stale confirm is corrected, expired sessions are handed over.
+    assert!(scheduler
+        .release(4, "a", Duration::from_secs(60))
+        .is_empty());
+    assert_eq!(
+        scheduler.confirm(5, "a", &a[..1]),
+        Err(vec![(5, a.clone())])
+    );
+    assert!(scheduler
+        .release(5, "a", Duration::from_secs(60))
+        .is_empty());
+    std::thread::sleep(Duration::from_millis(60));
+    assert_eq!(scheduler.expire().len(), 2);
+    assert_eq!(
+        scheduler.assignment(2).len() + scheduler.assignment(3).len(),
+        4
+    );
+    // Without a session the confirm is taken as an application.
+    let changes = scheduler.confirm(6, "a", &a).unwrap_err();
+    assert_eq!(changes[0].0, 6);
+    assert_eq!(changes[0].1.len(), 1);
+    // The rooms of a dismissed labour are handed over at once.
+    assert!(!scheduler.release(6, "a", Duration::from_secs(0)).is_empty());
+    assert_eq!(
+        scheduler.assignment(2).len() + scheduler.assignment(3).len(),
+        4
+    );
+}
+
+#[test]
+fn test_reshard() {
+    let rooms: BTreeSet<String> = (1..=100).map(|i| i.to_string()).collect();
+    let scheduler = Scheduler {
+        inner: Mutex::new(Inner {
+            pool: rooms.clone(),
+            rooms: rooms.clone(),
+            server_id: String::from("a"),
+            servers: once(String::from("a")).collect(),
+            ..Inner::default()
+        }),
+        grace: Duration::from_secs(0),
+    };
+    scheduler.apply(1, "x", 100);
+    // Rooms are split among the servers, each one is owned by exactly one.
+    let servers: BTreeSet<String> = ["a", "b", "c"].iter().map(|s| s.to_string()).collect();
+    let changes = scheduler.reshard(servers.clone());
+    let own = scheduler.assignment(1);
+    assert_eq!(changes, vec![(1, own.clone())]);
+    assert!(own.len() > 10 && own.len() < 60);
+    for room in &rooms {
+        let owned = own.contains(room);
+        assert_eq!(owner(room, &servers) == Some(&String::from("a")), owned);
+    }
+    assert!(scheduler.reshard(servers).is_empty());
+    // The rooms of a server which went away are taken over, the others stay.
+    let changes = scheduler.reshard(once(String::from("b")).collect());
+    assert_eq!(changes.len(), 1);
+    let after = scheduler.assignment(1);
+    assert!(own.iter().all(|room| after.contains(room)));
+    assert!(after.len() > own.len() && after.len() < 100);
+    scheduler.reshard(BTreeSet::new());
+    assert_eq!(scheduler.assignment(1).len(), 100);
+    // Rooms added or removed at runtime reach the labours.
+    let (n, changes) = scheduler.remove_rooms(&[String::from("1"), String::from("x")]);
+    assert_eq!(n, 1);
+    assert_eq!(changes, vec![(1, scheduler.assignment(1))]);
+    assert_eq!(scheduler.add_rooms(&[String::from("x")]).0, 1);
+    assert_eq!(scheduler.counts(), (100, 100, 100));
+}
@@ -1,6 +1,9 @@
+use crate::packet::constants::show_identity::category;
+use crate::packet::structs::VarInt;
+use crate::rate_limit::is_valid;
 use config::{Config, FileFormat, Value};
 use log::error;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::net::IpAddr;
 use std::path::Path;
 use std::str::FromStr;
@@ -11,9 +14,15 @@ pub struct Settings {
     pub path: String,
     pub ip: IpAddr,
     pub port: u16,
+    pub trusted_proxies: Vec<IpAddr>,
     pub token_files: TokenFiles,
     pub rate_limit: RateLimit,
+    /// Rate limits of the identity categories which differ from `rate_limit`.
+    pub category_rate_limits: BTreeMap<VarInt, RateLimit>,
+    pub task: Task,
+    pub report: Report,
     pub guard: Guard,
+    pub federation: Federation,
     pub log: Log,
 }
 
@@ -24,9 +33,23 @@ impl Default for Settings {
             path: String::from("config.yml"),
             ip: IpAddr::from([0, 0, 0, 0]),
             port: 8181,
+            trusted_proxies: Vec::new(),
             token_files: TokenFiles::default(),
             rate_limit: RateLimit::default(),
+            category_rate_limits: [(
+                category::SERVER,
+                RateLimit {
+                    interval: 1000,
+                    max_burst: 60,
+                },
+            )]
+            .iter()
+            .cloned()
+            .collect(),
+            task: Task::default(),
+            report: Report::default(),
             guard: Guard::default(),
+            federation: Federation::default(),
             log: Log::default(),
         }
     }
@@ -37,6 +60,7 @@ pub struct TokenFiles {
     pub client: String,
     pub server: String,
     pub admin: String,
+    pub reload_interval: u64,
 }
 
 impl Default for TokenFiles {
@@ -45,6 +69,7 @@ impl Default for TokenFiles {
             client: String::from("./client_tokens.txt"),
             server: String::from("./server_tokens.txt"),
             admin: String::from("./admin_tokens.txt"),
+            reload_interval: 60,
         }
     }
 }
@@ -64,19 +89,100 @@ impl Default for RateLimit {
     }
 }
 
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+pub struct Task {
+    pub room_file: String,
+    pub session_grace: u64,
+}
+
+impl Default for Task {
+    fn default() -> Self {
+        Task {
+            room_file: String::from("./rooms.txt"),
+            session_grace: 20000,
+        }
+    }
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+pub struct Report {
+    pub max_time: u32,
+    pub retention: u64,
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Report {
+            max_time: 3600,
+            retention: 600,
+        }
+    }
+}
+
 #[derive(Debug, Hash, PartialEq, Eq, Clone)]
 pub struct Guard {
     pub kick_count: i32,
-    pub ban_time: i64,
+    pub kick_window: i64,
+    pub kick_weights: BTreeMap<String, i32>,
+    pub ban_schedule: Vec<i64>,
     pub record_file: String,
+    pub save_interval: u64,
+    pub max_connections_per_ip: usize,
+    pub max_connections_per_subnet: usize,
+    pub max_connections_per_token: usize,
+    pub allowlist: Vec<String>,
+    pub banned_cidrs: Vec<String>,
+    pub ban_ipv6_subnet: bool,
 }
 
 impl Default for Guard {
     fn default() -> Self {
         Guard {
             kick_count: 10,
-            ban_time: 24,
+            kick_window: 24,
+            kick_weights: [
+                ("heartbeat_timeout", 1),
+                ("response_timeout", 1),
+                ("rate_limit", 2),
+                ("too_many_connections", 2),
+                ("unexpected_packet", 3),
+                ("invalid_packet", 3),
+                ("incorrect_data_format", 3),
+                ("unauthorized", 3),
+            ]
+            .iter()
+            .map(|(k, v)| (k.to_string(), *v))
+            .collect(),
+            ban_schedule: vec![1, 24, 168, 0],
             record_file: String::from("./guard_record"),
+            save_interval: 300,
+            max_connections_per_ip: 0,
+            max_connections_per_subnet: 0,
+            max_connections_per_token: 0,
+            allowlist: Vec::new(),
+            banned_cidrs: Vec::new(),
+            ban_ipv6_subnet: false,
+        }
+    }
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+pub struct Federation {
+    pub peers: Vec<String>,
+    pub token: String,
+    pub reconnect_interval: u64,
+    pub server_id: String,
+    pub max_hops: VarInt,
+}
+
+impl Default for Federation {
+    fn default() -> Self {
+        Federation {
+            peers: Vec::new(),
+            token: String::new(),
+            reconnect_interval: 5000,
+            server_id: String::new(),
+            max_hops: 8,
         }
     }
 }
@@ -108,6 +214,18 @@ fn get_int_from_map(map: &HashMap<String, Value>, k: &str) -> Option<i64> {
     map.get(k).and_then(|v| v.to_owned().into_int().ok())
 }
 
+fn get_int_array_from_map(map: &HashMap<String, Value>, k: &str) -> Option<Vec<i64>> {
+    map.get(k)
+        .and_then(|v| v.to_owned().into_array().ok())
+        .map(|a| a.into_iter().filter_map(|v| v.into_int().ok()).collect())
+}
+
+fn get_str_array_from_map(map: &HashMap<String, Value>, k: &str) -> Option<Vec<String>> {
+    map.get(k)
+        .and_then(|v| v.to_owned().into_array().ok())
+        .map(|a| a.into_iter().filter_map(|v| v.into_str().ok()).collect())
+}
+
 fn get_bool_from_map(map: &HashMap<String, Value>, k: &str) -> Option<bool> {
     map.get(k).and_then(|v| v.to_owned().into_bool().ok())
 }
@@ -168,6 +286,20 @@ impl Settings {
             self.port = x as u16;
         }
 
+        if let Ok(a) = cfg.get_array("trusted_proxies") {
+            self.trusted_proxies = a
+                .into_iter()
+                .filter_map(|v| v.into_str().ok())
+                .filter_map(|s| match IpAddr::from_str(&s) {
+                    Ok(ip) => Some(ip),
+                    Err(_) => {
+                        error!("Can't parse trusted proxy '{}', skipped.", s);
+                        None
+                    }
+                })
+                .collect();
+        }
+
         if let Ok(map) = cfg.get_table("token_files") {
             if let Some(x) = get_str_from_map(&map, "client") {
                 self.token_files.client = x;
@@ -178,6 +310,9 @@ impl Settings {
             if let Some(x) = get_str_from_map(&map, "admin") {
                 self.token_files.admin = x;
             }
+            if let Some(x) = get_int_from_map(&map, "reload_interval") {
+                self.token_files.reload_interval = x as u64;
+            }
         }
 
         if let Ok(map) = cfg.get_table("rate_limit") {
@@ -187,18 +322,126 @@ impl Settings {
             if let Some(x) = get_int_from_map(&map, "max_burst") {
                 self.rate_limit.max_burst = x as u32;
             }
+            if let Some(m) = get_map_from_map(&map, "categories") {
+                self.category_rate_limits.clear();
+                for (k, v) in m {
+                    let c = match k.as_str() {
+                        "client" => category::CLIENT,
+                        "server" => category::SERVER,
+                        "admin" => category::ADMIN,
+                        _ => {
+                            error!("Unknown category '{}' in rate_limit.categories.", k);
+                            continue;
+                        }
+                    };
+                    let map = match v.into_table() {
+                        Ok(map) => map,
+                        Err(_) => continue,
+                    };
+                    let mut rate_limit = self.rate_limit.clone();
+                    if let Some(x) = get_int_from_map(&map, "interval") {
+                        rate_limit.interval = x as u32;
+                    }
+                    if let Some(x) = get_int_from_map(&map, "max_burst") {
+                        rate_limit.max_burst = x as u32;
+                    }
+                    if !is_valid(&rate_limit) {
+                        error!(
+                            "Invalid rate limit of category '{}' in rate_limit.categories, skipped.",
+                            k
+                        );
+                        continue;
+                    }
+                    self.category_rate_limits.insert(c, rate_limit);
+                }
+            }
+        }
+
+        if let Ok(map) = cfg.get_table("task") {
+            if let Some(x) = get_str_from_map(&map, "room_file") {
+                self.task.room_file = x;
+            }
+            if let Some(x) = get_int_from_map(&map, "session_grace") {
+                self.task.session_grace = x as u64;
+            }
+        }
+
+        if let Ok(map) = cfg.get_table("report") {
+            if let Some(x) = get_int_from_map(&map, "max_time") {
+                self.report.max_time = x as u32;
+            }
+            if let Some(x) = get_int_from_map(&map, "retention") {
+                self.report.retention = x as u64;
+            }
         }
 
         if let Ok(map) = cfg.get_table("guard") {
             if let Some(x) = get_int_from_map(&map, "kick_count") {
                 self.guard.kick_count = x as i32;
             }
+            if let Some(x) = get_int_from_map(&map, "kick_window") {
+                self.guard.kick_window = x;
+            }
+            if let Some(m) = get_map_from_map(&map, "kick_weights") {
+                for (k, v) in m {
+                    if let Ok(x) = v.into_int() {
+                        self.guard.kick_weights.insert(k, x as i32);
+                    }
+                }
+            }
             if let Some(x) = get_int_from_map(&map, "ban_time") {
-                self.guard.ban_time = x;
+                self.guard.ban_schedule = vec![x];
+            }
+            if let Some(x) = get_int_array_from_map(&map, "ban_schedule") {
+                if !x.is_empty() {
+                    self.guard.ban_schedule = x;
+                }
             }
             if let Some(x) = get_str_from_map(&map, "record_file") {
                 self.guard.record_file = x;
             }
+            if let Some(x) = get_int_from_map(&map, "save_interval") {
+                self.guard.save_interval = x as u64;
+            }
+            if let Some(x) = get_int_from_map(&map, "max_connections_per_ip") {
+                self.guard.max_connections_per_ip = x as usize;
+            }
+            if let Some(x) = get_int_from_map(&map, "max_connections_per_subnet") {
+                self.guard.max_connections_per_subnet = x as usize;
+            }
+            if let Some(x) = get_int_from_map(&map, "max_connections_per_token") {
+                self.guard.max_connections_per_token = x as usize;
+            }
+            if let Some(x) = get_str_array_from_map(&map, "allowlist") {
+                self.guard.allowlist = x;
+            }
+            if let Some(x) = get_str_array_from_map(&map, "banned_cidrs") {
+                self.guard.banned_cidrs = x;
+            }
+            if let Some(x) = get_bool_from_map(&map, "ban_ipv6_subnet") {
+                self.guard.ban_ipv6_subnet = x;
+            }
+        }
+
+        if let Ok(map) = cfg.get_table("federation") {
+            if let Some(x) = get_str_array_from_map(&map, "peers") {
+                self.federation.peers = x;
+            }
+            if let Some(x) = get_str_from_map(&map, "token") {
+                self.federation.token = x;
+            }
+            if let Some(x) = get_int_from_map(&map, "reconnect_interval") {
+                self.federation.reconnect_interval = x as u64;
+            }
+            if let Some(x) = get_str_from_map(&map, "server_id") {
+                self.federation.server_id = x;
+            }
+            if let Some(x) = get_int_from_map(&map, "max_hops") {
+                self.federation.max_hops = x as VarInt;
+            }
+        }
+        if self.federation.server_id.is_empty() {
+            self.federation.server_id = format!("{:016x}", rand::random::<u64>());
         }
 
         if let Ok(map) = cfg.get_table("log") {
@@ -221,17 +464,64 @@ impl Settings {
 const DEFAULT_CONFIG_FILE: &str = "\
 ip: 0.0.0.0
 port: 8181
+# Reverse proxies whose X-Real-IP, X-Forwarded-For and Forwarded headers are trusted.
+trusted_proxies: []
 token_files:
   client: ./client_tokens.txt
   server: ./server_tokens.txt
   admin: ./admin_tokens.txt
+  reload_interval: 60
 rate_limit:
   interval: 10000
   max_burst: 6
+  # Rate limits of the identity categories, applied after Show Identity.
+  categories:
+    server:
+      interval: 1000
+      max_burst: 60
+task:
+  room_file: ./rooms.txt
+  session_grace: 20000
+report:
+  max_time: 3600
+  retention: 600
 guard:
   kick_count: 10
-  ban_time: 24
+  kick_window: 24
+  kick_weights:
+    heartbeat_timeout: 1
+    response_timeout: 1
+    rate_limit: 2
+    too_many_connections: 2
+    unexpected_packet: 3
+    invalid_packet: 3
+    incorrect_data_format: 3
+    unauthorized: 3
+  # Hours of each ban in order, 0 means permanent.
+  ban_schedule: [1, 24, 168, 0]
   record_file: ./guard_record
+  save_interval: 300
+  # Caps on simultaneous connections of an IP, a subnet (IPv4 /24, IPv6 /64) and
+  # a token, 0 means no cap. Set trusted_proxies first behind a reverse proxy.
+  max_connections_per_ip: 0
+  max_connections_per_subnet: 0
+  max_connections_per_token: 0
+  # IPs and tokens which are never kicked or banned.
+  allowlist: []
+  # Address blocks which are banned permanently, such as 10.0.0.0/8.
+  banned_cidrs: []
+  # Also ban the /64 of a banned IPv6 address.
+  ban_ipv6_subnet: false
+federation:
+  # WebSocket urls of the peer servers to connect to, such as ws://example.com:8181
+  peers: []
+  # Server token to show to the peer servers.
+  token: ''
+  reconnect_interval: 5000
+  # ID of this server in relayed reports, random if empty. It must be unique among the servers.
+  server_id: ''
+  # Relayed reports are dropped after passing this many servers.
+  max_hops: 8
 log:
   enable_console: true
   enable_file: true
@@ -0,0 +1,114 @@
+use crate::packet::constants::show_identity::category;
+use crate::packet::structs::VarInt;
+use crate::settings;
+use crate::settings::Settings;
+use crate::util::{read_list_file, try_read_list_file};
+use log::{info, warn};
+use std::collections::HashSet;
+use std::sync::{Mutex, RwLock};
+use std::time::SystemTime;
+
+#[derive(Debug, Default)]
+struct TokenSets {
+    client: HashSet<String>,
+    server: HashSet<String>,
+    admin: HashSet<String>,
+}
+
+impl TokenSets {
+    fn load(files: &settings::TokenFiles) -> TokenSets {
+        let sets = TokenSets {
+            client: read_list_file(&files.client).into_iter().collect(),
+            server: read_list_file(&files.server).into_iter().collect(),
+            admin: read_list_file(&files.admin).into_iter().collect(),
+        };
+        sets.log();
+        sets
+    }
+
+    /// Read the token files again, keeping the old set of a file which is missing
+    /// or can't be read rather than revoking all of its tokens.
+    fn reload(&self, files: &settings::TokenFiles) -> TokenSets {
+        let read = |path: &str, old: &HashSet<String>| match try_read_list_file(path) {
+            Some(tokens) => tokens.into_iter().collect(),
+            None => {
+                warn!(
+                    "Keep {} tokens of '{}' until it can be read.",
+                    old.len(),
+                    path
+                );
+                old.clone()
+            }
+        };
+        let sets = TokenSets {
+            client: read(&files.client, &self.client),
+            server: read(&files.server, &self.server),
+            admin: read(&files.admin, &self.admin),
+        };
+        sets.log();
+        sets
+    }
+
+    fn log(&self) {
+        info!(
+            "Loaded {} client tokens, {} server tokens and {} admin tokens.",
+            self.client.len(),
+            self.server.len(),
+            self.admin.len()
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct Tokens {
+    files: settings::TokenFiles,
+    sets: RwLock<TokenSets>,
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl Tokens {
+    pub fn new(settings: &Settings) -> Tokens {
+        let files = settings.token_files.clone();
+        let sets = TokenSets::load(&files);
+        let modified = modified_times(&files);
+        Tokens {
+            files,
+            sets: RwLock::new(sets),
+            modified: Mutex::new(modified),
+        }
+    }
+
+    pub fn check(&self, category: VarInt, token: &str) -> bool {
+        let sets = self.sets.read().unwrap();
+        match category {
+            category::CLIENT => sets.client.contains(token),
+            category::SERVER => sets.server.contains(token),
+            category::ADMIN => sets.admin.contains(token),
+            _ => false,
+        }
+    }
+
+    /// Read all token files again and swap the new sets in at once.
+    pub fn reload(&self) {
+        let sets = self.sets.read().unwrap().reload(&self.files);
+        *self.modified.lock().unwrap() = modified_times(&self.files);
+        *self.sets.write().unwrap() = sets;
+    }
+
+    /// Reload only if any token file has been modified since the last load.
+    pub fn reload_if_modified(&self) -> bool {
+        if *self.modified.lock().unwrap() == modified_times(&self.files) {
+            return false;
+        }
+        info!("Token files have been modified, reloading.");
+        self.reload();
+        true
+    }
+}
+
+fn modified_times(files: &settings::TokenFiles) -> Vec<Option<SystemTime>> {
+    [&files.client, &files.server, &files.admin]
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
@@ -2,6 +2,9 @@ use crate::guard::Guard;
 use crate::settings::Settings;
 use actix::{Actor, ActorContext, Running, StreamHandler};
 use actix_web_actors::ws;
+use log::error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
 
 pub mod timer;
 
@@ -9,3 +12,50 @@ pub struct WebData {
     pub settings: Settings,
     pub guard: Guard,
 }
+
+/// Read a file with one entry per line, creating it if it doesn't exist.
+pub fn read_list_file(path: &str) -> Vec<String> {
+    let p = Path::new(path);
+    if !p.is_file() && std::fs::write(p, "").is_err() {
+        error!("Can't create file '{}'.", path);
+    }
+    try_read_list_file(path).unwrap_or_default()
+}
+
+/// Read a file with one entry per line, `None` if it is missing or can't be read.
+pub fn try_read_list_file(path: &str) -> Option<Vec<String>> {
+    match std::fs::read_to_string(path) {
+        Ok(s) => Some(parse_list(&s)),
+        Err(e) => {
+            error!("Can't read file '{}': {}.", path, e);
+            None
+        }
+    }
+}
+
+/// The /24 network of an IPv4 address or the /64 network of an IPv6 address.
+pub fn subnet(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(*ip) & !0xFF)),
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(*ip) & !(u64::MAX as u128))),
+    }
+}
+
+/// Blank lines and lines starting with `#` are ignored.
+fn parse_list(s: &str) -> Vec<String> {
+    s.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_owned())
+        .collect()
+}
+
+#[test]
+fn test() {
+    let list = parse_list("aaa\n  bbb  \n\n# ccc\r\nddd\r\n");
+    assert_eq!(list, vec!["aaa", "bbb", "ddd"]);
+    let ip: IpAddr = "192.168.1.23".parse().unwrap();
+    assert_eq!(subnet(&ip), "192.168.1.0".parse::<IpAddr>().unwrap());
+    let ip: IpAddr = "2001:db8:1:2:3:4:5:6".parse().unwrap();
+    assert_eq!(subnet(&ip), "2001:db8:1:2::".parse::<IpAddr>().unwrap());
+}
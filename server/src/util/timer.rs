@@ -88,3 +88,17 @@ where
         }
     }
 }
+
+/// Run `f` every `duration` on a dedicated thread.
+pub fn run_interval<F>(name: &str, duration: Duration, f: F)
+where
+    F: Fn() + Send + 'static,
+{
+    std::thread::Builder::new()
+        .name(name.to_owned())
+        .spawn(move || loop {
+            std::thread::sleep(duration);
+            f();
+        })
+        .expect("Can't spawn interval thread!");
+}